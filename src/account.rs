@@ -0,0 +1,1020 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::crypto;
+use crate::database::ConnectionInfo;
+use crate::hook::ActionType;
+use crate::permission;
+use crate::prelude::*;
+use crate::token::{self, TokenPurpose};
+use crate::CONFIGURATION;
+use surrealdb::sql::Thing;
+
+/// The account taking the place of deleted accounts in the records kept for bookkeeping.
+pub const TOMBSTONE: (&str, &str) = ("account", "tombstone");
+/// The fields of an account encrypted with the key derived from its password, which nobody but
+/// the account itself is able to read.
+pub const ENCRYPTED_FIELDS: &[&str] = &["private_notes"];
+/// How long a mailed password reset token is valid.
+const PASSWORD_RESET_LIFETIME: &str = "1h";
+/// How long new employees have to set their password.
+const INVITATION_LIFETIME: &str = "7d";
+/// How long the confirmation of a new mail address is valid.
+const MAIL_CHANGE_LIFETIME: &str = "1d";
+/// How long an account has to wait before another verification mail gets sent.
+const VERIFICATION_RESEND_INTERVAL: &str = "5m";
+
+/// Customers sign up themselves and only see their own requests and tasks, employees are created
+/// by admins and work on the requests and tasks of everyone. The frontend picks its dashboard
+/// accordingly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AccountType {
+    Customer,
+    Employee,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct Deletion {
+    id: Thing,
+    account: Thing,
+    requested_by: Thing,
+    due_at: String,
+    cancelled_at: Option<String>,
+    completed_at: Option<String>,
+    summary: Option<serde_json::Value>,
+    created_at: String,
+}
+
+/// The nonce of the first password hash of the given mail. Unknown mails get a stable fake nonce,
+/// so the response does not reveal which accounts exist.
+pub async fn nonce(info: &ConnectionInfo, mail: &str) -> Result<String> {
+    let nonce: Option<String> = info
+        .connection
+        .statement(
+            "fetching nonce",
+            "RETURN (SELECT VALUE nonce FROM account WHERE mail = $mail)[0]
+                OR string::slice(crypto::sha256(string::concat($mail, $pepper)), 0, 32)",
+        )
+        .bind(("mail", mail))
+        .bind(("pepper", CONFIGURATION.secret_key.as_str()))
+        .await?
+        .take(0)?;
+
+    nonce.ok_or(ApplicationError::InternalServerError)
+}
+
+/// The first hash of the password of the given mail, see [crypto::derive_key]. Its encoded form
+/// is the `$password` of the `account` scope.
+pub async fn derive_key(info: &ConnectionInfo, mail: &str, password: &str) -> Result<Vec<u8>> {
+    crypto::derive_key(password, nonce(info, mail).await?.as_str())
+}
+
+/// Accounts of 0.1.0 have no nonce and store the argon2 hash of the plain password. Replace it
+/// with the hash of the derived key once the given password matches. As those accounts never had
+/// encrypted fields, nothing has to be re-encrypted.
+#[instrument(skip(info, password))]
+pub async fn upgrade_password(info: &ConnectionInfo, mail: &str, password: &str) -> Result<()> {
+    let legacy: Option<Thing> = info
+        .connection
+        .statement(
+            "checking legacy password",
+            "SELECT VALUE id FROM account
+                WHERE mail = $mail AND nonce IS NONE AND crypto::argon2::compare(password, $password)",
+        )
+        .bind(("mail", mail))
+        .bind(("password", password))
+        .await?
+        .take(0)?;
+    let Some(account) = legacy else {
+        return Ok(());
+    };
+
+    let nonce = crypto::generate_nonce();
+    let key = crypto::derive_key(password, nonce.as_str())?;
+    info.connection
+        .statement(
+            "upgrading legacy password",
+            "UPDATE $account SET nonce = $nonce, password = crypto::argon2::generate($password)
+                WHERE nonce IS NONE",
+        )
+        .bind(("account", &account))
+        .bind(("nonce", nonce))
+        .bind(("password", crypto::encode_key(&key)))
+        .await?;
+    info!("Upgraded the password hash of {}", account);
+
+    Ok(())
+}
+
+fn encrypted_field(field: &str) -> Result<&'static str> {
+    ENCRYPTED_FIELDS
+        .iter()
+        .find(|encrypted| field.eq(**encrypted))
+        .copied()
+        .ok_or_else(|| ApplicationError::BadRequest(format!("{field:?} is not encrypted")))
+}
+
+/// Read one of the encrypted fields of the signed in account.
+#[instrument(skip(connection, key))]
+pub async fn read_encrypted(
+    connection: &DatabaseConnection,
+    key: &[u8],
+    field: &str,
+) -> Result<Option<String>> {
+    let field = encrypted_field(field)?;
+    let encrypted: Option<String> = connection
+        .statement(
+            "reading encrypted field",
+            format!("SELECT VALUE {field} FROM $auth.id"),
+        )
+        .await?
+        .take(0)?;
+
+    encrypted
+        .map(|encrypted| {
+            String::from_utf8(crypto::decrypt(key, encrypted.as_str())?)
+                .map_err(|_| ApplicationError::CryptoError)
+        })
+        .transpose()
+}
+
+/// Write one of the encrypted fields of the signed in account.
+#[instrument(skip(connection, key, value))]
+pub async fn write_encrypted(
+    connection: &DatabaseConnection,
+    key: &[u8],
+    field: &str,
+    value: Option<&str>,
+) -> Result<()> {
+    let field = encrypted_field(field)?;
+    let encrypted = value
+        .map(|value| crypto::encrypt(key, value.as_bytes()))
+        .transpose()?;
+
+    connection
+        .statement(
+            "writing encrypted field",
+            format!("UPDATE $auth.id SET {field} = $value"),
+        )
+        .bind(("value", encrypted))
+        .await?;
+
+    Ok(())
+}
+
+/// Change the password of the signed in account. As the key of the encrypted fields is derived
+/// from the password, all of them get re-encrypted with the new one.
+#[instrument(skip_all)]
+pub async fn change_password(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+    current: &str,
+    password: &str,
+) -> Result<()> {
+    let account = crate::session::account(connection).await?;
+    let mail: Option<String> = info
+        .connection
+        .statement("fetching account mail", "SELECT VALUE mail FROM $account")
+        .bind(("account", &account))
+        .await?
+        .take(0)?;
+    let mail = mail.ok_or(ApplicationError::Unauthorized)?;
+
+    let current_key = derive_key(info, mail.as_str(), current).await?;
+    let record: Option<serde_json::Value> = info
+        .connection
+        .statement(
+            "checking password",
+            "SELECT * FROM $account WHERE crypto::argon2::compare(password, $password)",
+        )
+        .bind(("account", &account))
+        .bind(("password", crypto::encode_key(&current_key)))
+        .await?
+        .take(0)?;
+    let record = record.ok_or(ApplicationError::Unauthorized)?;
+
+    let nonce = crypto::generate_nonce();
+    let key = crypto::derive_key(password, nonce.as_str())?;
+    let mut changes = serde_json::Map::new();
+    changes.insert("nonce".to_owned(), nonce.into());
+    for field in ENCRYPTED_FIELDS {
+        if let Some(encrypted) = record.get(field).and_then(|value| value.as_str()) {
+            let decrypted = crypto::decrypt(&current_key, encrypted)?;
+            changes.insert(
+                field.to_string(),
+                crypto::encrypt(&key, decrypted.as_slice())?.into(),
+            );
+        }
+    }
+
+    info.connection
+        .statement(
+            "changing password",
+            "BEGIN TRANSACTION;
+            UPDATE $account MERGE $changes;
+            UPDATE $account SET password = crypto::argon2::generate($password);
+            COMMIT TRANSACTION;",
+        )
+        .bind(("account", &account))
+        .bind(("changes", changes))
+        .bind(("password", crypto::encode_key(&key)))
+        .await?;
+
+    Ok(())
+}
+
+/// Verify the mail address of an account with the token of its verification mail. Unverified
+/// accounts are unable to open requests or to write messages.
+#[instrument(skip_all)]
+pub async fn verify_mail(info: &ConnectionInfo, token: &str) -> Result<()> {
+    let consumed = token::consume(&info.connection, TokenPurpose::Verification, token).await?;
+
+    info.connection
+        .statement(
+            "verifying account",
+            "UPDATE $account SET verified_at = time::now() WHERE verified_at IS NONE",
+        )
+        .bind(("account", &consumed.account))
+        .await?;
+
+    Ok(())
+}
+
+/// Send another verification mail to the signed in account, at most once per interval.
+#[instrument(skip_all)]
+pub async fn resend_verification(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+) -> Result<()> {
+    let account = crate::session::account(connection).await?;
+
+    let mut response = info
+        .connection
+        .statement(
+            "checking verification",
+            "RETURN $account.verified_at IS NOT NONE",
+        )
+        .query(
+            "SELECT count() FROM token
+                WHERE account = $account AND purpose = $purpose
+                    AND created_at > time::now() - type::duration($interval)
+                GROUP ALL",
+        )
+        .bind(("account", &account))
+        .bind(("purpose", TokenPurpose::Verification))
+        .bind(("interval", VERIFICATION_RESEND_INTERVAL))
+        .await?;
+    if response.take::<Option<bool>>(0)?.unwrap_or_default() {
+        return Err(ApplicationError::BadRequest(
+            "The account is already verified".to_owned(),
+        ));
+    }
+    if response
+        .take::<Option<i64>>((1, "count"))?
+        .unwrap_or_default()
+        > 0
+    {
+        return Err(ApplicationError::TooManyRequests);
+    }
+
+    info.connection
+        .statement(
+            "resending verification",
+            "RETURN fn::send_verification($account)",
+        )
+        .bind(("account", &account))
+        .await?;
+
+    Ok(())
+}
+
+/// Mail a password reset link to the account of the given mail. Succeeds for unknown mails as
+/// well, so the response does not reveal which accounts exist.
+#[instrument(skip(info))]
+pub async fn request_password_reset(info: &ConnectionInfo, mail: &str) -> Result<()> {
+    mail_password_reset(info, mail, PASSWORD_RESET_LIFETIME).await
+}
+
+async fn mail_password_reset(info: &ConnectionInfo, mail: &str, lifetime: &str) -> Result<()> {
+    info.connection
+        .statement(
+            "requesting password reset",
+            "LET $account = (SELECT id, mail, locale FROM account WHERE mail = $mail)[0];
+            IF $account.id IS NOT NONE THEN {
+                LET $token = fn::issue_token($account.id, $purpose, type::duration($lifetime), NONE);
+                CREATE mail CONTENT {
+                    recipient: $account.mail,
+                    type: $type,
+                    locale: $account.locale,
+                    link: string::concat(\"/password/reset?token=\", $token)
+                };
+                CREATE ONLY hook;
+            } END;",
+        )
+        .bind(("mail", mail))
+        .bind(("purpose", TokenPurpose::PasswordReset))
+        .bind(("lifetime", lifetime))
+        .bind(("type", ActionType::PasswordReset))
+        .await?;
+
+    Ok(())
+}
+
+/// Set a new password with a mailed reset token and end all sessions of the account, including the
+/// API tokens. The key of the encrypted fields is lost with the old password, therefore they get
+/// cleared.
+#[instrument(skip_all)]
+pub async fn reset_password(info: &ConnectionInfo, token: &str, password: &str) -> Result<()> {
+    let consumed = token::consume(&info.connection, TokenPurpose::PasswordReset, token).await?;
+
+    let nonce = crypto::generate_nonce();
+    let key = crypto::derive_key(password, nonce.as_str())?;
+    let mut changes = serde_json::Map::new();
+    changes.insert("nonce".to_owned(), nonce.into());
+    for field in ENCRYPTED_FIELDS {
+        changes.insert(field.to_string(), serde_json::Value::Null);
+    }
+
+    info.connection
+        .statement(
+            "resetting password",
+            "BEGIN TRANSACTION;
+            UPDATE $account MERGE $changes;
+            UPDATE $account SET password = crypto::argon2::generate($password);
+            DELETE session WHERE account = $account;
+            DELETE api_token WHERE account = $account;
+            COMMIT TRANSACTION;",
+        )
+        .bind(("account", &consumed.account))
+        .bind(("changes", changes))
+        .bind(("password", crypto::encode_key(&key)))
+        .await?;
+    info!("Reset the password of {}", consumed.account);
+
+    Ok(())
+}
+
+/// The type of the signed in account.
+#[instrument(skip_all)]
+pub async fn account_type(connection: &DatabaseConnection) -> Result<AccountType> {
+    let ty: Option<AccountType> = connection
+        .statement("fetching account type", "RETURN $auth.type")
+        .await?
+        .take(0)?;
+
+    ty.ok_or(ApplicationError::Unauthorized)
+}
+
+/// Create an employee account on behalf of an admin. Nobody knows the initial password, the
+/// employee sets one through the mailed reset link.
+#[instrument(skip(info, connection))]
+pub async fn create_employee(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+    first_name: &str,
+    last_name: &str,
+    mail: &str,
+) -> Result<Thing> {
+    permission::authorize(connection, Permission::Admin).await?;
+
+    let password = crypto::encode_key(&rand::random::<[u8; crypto::KEY_LENGTH]>());
+    let created: Option<Thing> = info
+        .connection
+        .statement(
+            "creating employee",
+            "CREATE ONLY account CONTENT {
+                first_name: $first_name,
+                last_name: $last_name,
+                mail: $mail,
+                type: $type,
+                nonce: $nonce,
+                password: crypto::argon2::generate($password),
+                verified_at: time::now()
+            } RETURN VALUE id",
+        )
+        .bind(("first_name", first_name))
+        .bind(("last_name", last_name))
+        .bind(("mail", mail))
+        .bind(("type", AccountType::Employee))
+        .bind(("nonce", crypto::generate_nonce()))
+        .bind(("password", password))
+        .await?
+        .take(0)?;
+    let created = created.ok_or(ApplicationError::InternalServerError)?;
+    mail_password_reset(info, mail, INVITATION_LIFETIME).await?;
+    info!("Created the employee {}", created);
+
+    Ok(created)
+}
+
+/// Start changing the mail address of the signed in account. The new address gets a confirmation
+/// link, the current one a notice; nothing changes before the confirmation.
+#[instrument(skip(info, connection))]
+pub async fn request_mail_change(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+    mail: &str,
+) -> Result<()> {
+    let account = crate::session::account(connection).await?;
+
+    let mut response = info
+        .connection
+        .statement("validating mail", "RETURN string::is::email($mail)")
+        .query("SELECT count() FROM account WHERE mail = $mail GROUP ALL")
+        .bind(("mail", mail))
+        .await?;
+    if !response.take::<Option<bool>>(0)?.unwrap_or_default() {
+        return Err(ApplicationError::BadRequest(
+            "The mail address is invalid".to_owned(),
+        ));
+    }
+    if response
+        .take::<Option<i64>>((1, "count"))?
+        .unwrap_or_default()
+        > 0
+    {
+        return Err(ApplicationError::BadRequest(
+            "The mail address is already in use".to_owned(),
+        ));
+    }
+
+    info.connection
+        .statement(
+            "requesting mail change",
+            "LET $token = fn::issue_token($account, $purpose, type::duration($lifetime), { mail: $mail });
+            CREATE mail CONTENT {
+                recipient: $mail,
+                type: $confirmation,
+                locale: $account.locale,
+                link: string::concat(\"/mail/confirm?token=\", $token)
+            };
+            CREATE mail CONTENT {
+                recipient: $account.mail,
+                type: $notice,
+                locale: $account.locale,
+                link: \"/password/reset\"
+            };
+            CREATE ONLY hook;",
+        )
+        .bind(("account", &account))
+        .bind(("mail", mail))
+        .bind(("purpose", TokenPurpose::MailChange))
+        .bind(("lifetime", MAIL_CHANGE_LIFETIME))
+        .bind(("confirmation", ActionType::MailChangeConfirmation))
+        .bind(("notice", ActionType::MailChangeNotice))
+        .await?;
+
+    Ok(())
+}
+
+/// Swap the mail address with the one confirmed by the given token. Pending mails follow the
+/// account to its new address, except the notice about the change itself.
+#[instrument(skip(info, token))]
+pub async fn confirm_mail_change(info: &ConnectionInfo, token: &str) -> Result<()> {
+    let consumed = token::consume(&info.connection, TokenPurpose::MailChange, token).await?;
+    let mail = consumed
+        .payload
+        .as_ref()
+        .and_then(|payload| payload.get("mail"))
+        .and_then(|mail| mail.as_str())
+        .ok_or_else(|| {
+            ApplicationError::BadRequest("The token is invalid or expired".to_owned())
+        })?;
+
+    // the unique mailIndex refuses addresses taken since the request
+    info.connection
+        .statement(
+            "changing mail",
+            "BEGIN TRANSACTION;
+            LET $old = $account.mail;
+            UPDATE mail SET recipient = $mail
+                WHERE recipient = $old AND state = \"pending\" AND type != $notice;
+            UPDATE $account SET mail = $mail, verified_at = time::now();
+            COMMIT TRANSACTION;",
+        )
+        .bind(("account", &consumed.account))
+        .bind(("mail", mail))
+        .bind(("notice", ActionType::MailChangeNotice))
+        .await?;
+    info!("Changed the mail address of {}", consumed.account);
+
+    Ok(())
+}
+
+/// Schedule the deletion of the given account after the configured grace period. Accounts are
+/// allowed to delete themselves, admins are allowed to delete everyone.
+#[instrument(skip(connection))]
+pub async fn request_deletion(
+    connection: &DatabaseConnection,
+    account: &Thing,
+) -> Result<Deletion> {
+    if Thing::from(TOMBSTONE).eq(account) {
+        return Err(ApplicationError::BadRequest(
+            "The tombstone account cannot be deleted".to_owned(),
+        ));
+    }
+
+    let scheduled: Option<i64> = connection
+        .statement(
+            "counting scheduled deletions",
+            "SELECT count() FROM deletion
+                WHERE account = $account AND cancelled_at IS NONE AND completed_at IS NONE GROUP ALL",
+        )
+        .bind(("account", account))
+        .await?
+        .take((0, "count"))?;
+    if scheduled.unwrap_or_default() > 0 {
+        return Err(ApplicationError::BadRequest(format!(
+            "The deletion of {} is already scheduled",
+            account
+        )));
+    }
+
+    let deletion: Option<Deletion> = connection
+        .statement(
+            "scheduling deletion",
+            "CREATE ONLY deletion CONTENT {
+                account: $account,
+                due_at: time::now() + type::duration($grace_period)
+            }",
+        )
+        .bind(("account", account))
+        .bind(("grace_period", CONFIGURATION.deletion_grace_period.as_str()))
+        .await?
+        .take(0)?;
+
+    deletion
+        .ok_or_else(|| ApplicationError::Forbidden(format!("Not allowed to delete {}", account)))
+}
+
+/// Cancel the scheduled deletion of the given account during the grace period.
+#[instrument(skip(connection))]
+pub async fn cancel_deletion(connection: &DatabaseConnection, account: &Thing) -> Result<()> {
+    let cancelled: Vec<Deletion> = connection
+        .statement(
+            "cancelling deletion",
+            "UPDATE deletion SET cancelled_at = time::now()
+                WHERE account = $account AND cancelled_at IS NONE AND completed_at IS NONE",
+        )
+        .bind(("account", account))
+        .await?
+        .take(0)?;
+
+    if cancelled
+        .iter()
+        .all(|deletion| deletion.cancelled_at.is_none())
+    {
+        return Err(ApplicationError::BadRequest(format!(
+            "No deletion of {} is scheduled",
+            account
+        )));
+    }
+
+    Ok(())
+}
+
+/// Carry out all deletions whose grace period is over, called by the maintenance.
+///
+/// Tasks are kept for bookkeeping and handed to the tombstone account, just like the authorship of
+/// messages and notifications and the entries of the action log. Everything else belonging to the
+/// account is removed.
+#[instrument(skip_all)]
+pub async fn process_deletions(connection: &DatabaseConnection) -> Result<()> {
+    let due: Vec<Deletion> = connection
+        .statement(
+            "collecting due deletions",
+            "SELECT * FROM deletion
+                WHERE due_at <= time::now() AND cancelled_at IS NONE AND completed_at IS NONE",
+        )
+        .await?
+        .take(0)?;
+
+    // the deletion of the last admin is refused, which must not hold back the others
+    for deletion in due {
+        if let Err(error) = delete(connection, &deletion).await {
+            error!("Unable to delete {}: {}", deletion.account, error);
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete the account of a due deletion along with its data.
+async fn delete(connection: &DatabaseConnection, deletion: &Deletion) -> Result<()> {
+    let exports: Vec<Thing> = connection
+        .statement(
+            "collecting exports of deleted account",
+            "SELECT VALUE id FROM export WHERE account = $account",
+        )
+        .bind(("account", &deletion.account))
+        .await?
+        .take(0)?;
+
+    connection
+        .statement(
+            "deleting account",
+            "BEGIN TRANSACTION;
+            LET $requests = (SELECT VALUE id FROM task_request WHERE customer = $account);
+            LET $summary = {
+                tasks: (SELECT count() FROM task WHERE customer = $account GROUP ALL)[0].count OR 0,
+                task_requests: array::len($requests),
+                messages: (SELECT count() FROM message WHERE author = $account GROUP ALL)[0].count OR 0
+            };
+
+            DELETE revision WHERE record INSIDE $requests OR record.reference INSIDE $requests;
+            DELETE message WHERE reference INSIDE $requests;
+            DELETE $requests;
+            UPDATE task SET customer = $tombstone WHERE customer = $account;
+            UPDATE message SET author = $tombstone WHERE author = $account;
+            UPDATE revision SET editor = NONE WHERE editor = $account;
+            UPDATE notification SET by = $tombstone WHERE by = $account;
+            DELETE notification WHERE `for` = $account;
+            DELETE mail WHERE recipient = $account.mail AND state != \"delivered\";
+            DELETE has WHERE in = $account;
+            DELETE member_of WHERE in = $account;
+            -- after the relations, as their events log against the account as well
+            UPDATE action_log SET author = $tombstone WHERE author = $account;
+            UPDATE action_log SET target = $tombstone WHERE target = $account;
+            DELETE export WHERE account = $account;
+            DELETE session WHERE account = $account;
+            DELETE api_token WHERE account = $account;
+            DELETE token WHERE account = $account;
+            DELETE $account;
+
+            UPDATE $deletion SET completed_at = time::now(), summary = $summary;
+            COMMIT TRANSACTION;",
+        )
+        .bind(("account", &deletion.account))
+        .bind(("deletion", &deletion.id))
+        .bind(("tombstone", Thing::from(TOMBSTONE)))
+        .await?;
+
+    for export in exports {
+        if let Err(error) = tokio::fs::remove_file(crate::export::path(&export)).await {
+            warn!("Unable to remove the archive of {}: {}", export, error);
+        }
+    }
+    info!(
+        "Deleted {} as requested by {}",
+        deletion.account, deletion.requested_by
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixture::{self, fresh};
+
+    #[tokio::test]
+    async fn test_change_password() -> Result<()> {
+        let info = fresh().await?;
+        fixture::customer(&info, "alice").await?;
+        let connection = fixture::session(&info, "alice").await?;
+        let mail = &fixture::mail("alice");
+
+        let key = derive_key(&info, mail, fixture::PASSWORD).await?;
+        write_encrypted(&connection, &key, "private_notes", Some("notes")).await?;
+        assert_eq!(
+            Some("notes".to_owned()),
+            read_encrypted(&connection, &key, "private_notes").await?
+        );
+        assert!(read_encrypted(&connection, &key, "first_name")
+            .await
+            .is_err());
+        // the hash of the key is all the database knows
+        let stored: Option<String> = info
+            .connection
+            .query("SELECT VALUE private_notes FROM account:alice")
+            .await?
+            .take(0)?;
+        assert!(!stored.unwrap().contains("notes"));
+
+        assert!(change_password(&info, &connection, "wrong", "changed")
+            .await
+            .is_err());
+        change_password(&info, &connection, fixture::PASSWORD, "changed").await?;
+        assert!(read_encrypted(&connection, &key, "private_notes")
+            .await
+            .is_err());
+        let key = derive_key(&info, mail, "changed").await?;
+        assert_eq!(
+            Some("notes".to_owned()),
+            read_encrypted(&connection, &key, "private_notes").await?
+        );
+
+        assert!(
+            crate::session::login(&info, fixture::CLIENT, mail, fixture::PASSWORD, None)
+                .await
+                .is_err()
+        );
+        crate::session::login(&info, fixture::CLIENT, mail, "changed", None).await?;
+        // unknown mails get a stable nonce as well
+        assert_eq!(
+            nonce(&info, "unknown@yaud.example").await?,
+            nonce(&info, "unknown@yaud.example").await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_legacy_password() -> Result<()> {
+        let info = fresh().await?;
+        let mail = "erik@yaud.example";
+        info.connection
+            .query("CREATE account:erik CONTENT { first_name: \"Erik\", last_name: \"Lang\", mail: $mail, password: crypto::argon2::generate(\"legacy\") }")
+            .bind(("mail", mail))
+            .await?
+            .check()?;
+
+        assert!(
+            crate::session::login(&info, fixture::CLIENT, mail, "wrong", None)
+                .await
+                .is_err()
+        );
+        crate::session::login(&info, fixture::CLIENT, mail, "legacy", None).await?;
+        let nonce: Option<String> = info
+            .connection
+            .query("SELECT VALUE nonce FROM account:erik")
+            .await?
+            .take(0)?;
+        assert!(nonce.is_some());
+        // the upgraded account uses the derived key from now on
+        let tokens = crate::session::login(&info, fixture::CLIENT, mail, "legacy", None).await?;
+        let connection = crate::session::authenticate(&info, tokens.access_token.as_str()).await?;
+        let key = derive_key(&info, mail, "legacy").await?;
+        write_encrypted(&connection, &key, "private_notes", Some("notes")).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verification() -> Result<()> {
+        let info = fresh().await?;
+        let key =
+            crypto::derive_key("password", "nonce-of-dora").map(|key| crypto::encode_key(&key))?;
+        info.connection
+            .query("CREATE account:dora CONTENT { first_name: \"Dora\", last_name: \"Klein\", mail: \"dora@yaud.example\", nonce: \"nonce-of-dora\", password: crypto::argon2::generate($password) }")
+            .bind(("password", key))
+            .await?
+            .check()?;
+        let tokens = crate::session::login(
+            &info,
+            fixture::CLIENT,
+            "dora@yaud.example",
+            "password",
+            None,
+        )
+        .await?;
+        let connection = crate::session::authenticate(&info, tokens.access_token.as_str()).await?;
+
+        // unverified accounts are unable to open requests
+        let created: Vec<serde_json::Value> = connection
+            .query("CREATE task_request CONTENT { title: \"title\", description: \"description\" }")
+            .await?
+            .take(0)
+            .unwrap_or_default();
+        assert!(created.is_empty());
+        assert!(matches!(
+            resend_verification(&info, &connection).await,
+            Err(ApplicationError::TooManyRequests)
+        ));
+
+        let links: Vec<String> = info
+            .connection
+            .query("SELECT VALUE link FROM mail WHERE type = \"account_verification\"")
+            .await?
+            .take(0)?;
+        assert_eq!(1, links.len());
+        verify_mail(&info, links[0].split("token=").nth(1).unwrap()).await?;
+
+        let created: Vec<serde_json::Value> = connection
+            .query("CREATE task_request CONTENT { title: \"title\", description: \"description\" }")
+            .await?
+            .take(0)?;
+        assert_eq!(1, created.len());
+        assert!(resend_verification(&info, &connection).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_password_reset() -> Result<()> {
+        let info = fresh().await?;
+        fixture::customer(&info, "alice").await?;
+        let mail = &fixture::mail("alice");
+        let tokens =
+            crate::session::login(&info, fixture::CLIENT, mail, fixture::PASSWORD, None).await?;
+
+        request_password_reset(&info, "unknown@yaud.example").await?;
+        request_password_reset(&info, mail).await?;
+        let links: Vec<String> = info
+            .connection
+            .query("SELECT VALUE link FROM mail WHERE type = \"password_reset\"")
+            .await?
+            .take(0)?;
+        assert_eq!(1, links.len());
+        let token = links[0].split("token=").nth(1).unwrap();
+
+        assert!(reset_password(&info, "invalid", "changed").await.is_err());
+        reset_password(&info, token, "changed").await?;
+        assert!(reset_password(&info, token, "again").await.is_err());
+
+        assert!(
+            crate::session::authenticate(&info, tokens.access_token.as_str())
+                .await
+                .is_err()
+        );
+        assert!(
+            crate::session::login(&info, fixture::CLIENT, mail, fixture::PASSWORD, None)
+                .await
+                .is_err()
+        );
+        crate::session::login(&info, fixture::CLIENT, mail, "changed", None).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_account_types() -> Result<()> {
+        let info = fresh().await?;
+        fixture::owner(&info, "staff").await?;
+        fixture::customer(&info, "alice").await?;
+        let staff = fixture::session(&info, "staff").await?;
+        let alice = fixture::session(&info, "alice").await?;
+        assert_eq!(AccountType::Employee, account_type(&staff).await?);
+        assert_eq!(AccountType::Customer, account_type(&alice).await?);
+
+        // the type is never changed by the account itself
+        alice
+            .query("UPDATE account:alice SET type = \"employee\"")
+            .await?
+            .check()?;
+        assert_eq!(AccountType::Customer, account_type(&alice).await?);
+
+        let mail = "dora@yaud.example";
+        assert!(create_employee(&info, &alice, "Dora", "Klein", mail)
+            .await
+            .is_err());
+        let dora = create_employee(&info, &staff, "Dora", "Klein", mail).await?;
+        let options: Option<serde_json::Value> = info
+            .connection
+            .query("SELECT VALUE options FROM $account")
+            .bind(("account", &dora))
+            .await?
+            .take(0)?;
+        assert_eq!(
+            Some(true),
+            options.unwrap()["notify_task_created"].as_bool()
+        );
+
+        let links: Vec<String> = info
+            .connection
+            .query(
+                "SELECT VALUE link FROM mail WHERE type = \"password_reset\" AND recipient = $mail",
+            )
+            .bind(("mail", mail))
+            .await?
+            .take(0)?;
+        assert_eq!(1, links.len());
+        reset_password(&info, links[0].split("token=").nth(1).unwrap(), "password").await?;
+        let tokens = crate::session::login(&info, fixture::CLIENT, mail, "password", None).await?;
+        let connection = crate::session::authenticate(&info, tokens.access_token.as_str()).await?;
+        assert_eq!(AccountType::Employee, account_type(&connection).await?);
+        assert!(permission::has_permission(&connection, Permission::TaskSelect).await?);
+        assert!(!permission::has_permission(&connection, Permission::Admin).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mail_change() -> Result<()> {
+        let info = fresh().await?;
+        fixture::customer(&info, "alice").await?;
+        fixture::customer(&info, "bob").await?;
+        let connection = fixture::session(&info, "alice").await?;
+        let mail = &fixture::mail("alice");
+
+        assert!(request_mail_change(&info, &connection, "invalid")
+            .await
+            .is_err());
+        assert!(
+            request_mail_change(&info, &connection, &fixture::mail("bob"))
+                .await
+                .is_err()
+        );
+        info.connection
+            .query("CREATE mail CONTENT { recipient: $mail, type: \"password_reset\" }")
+            .bind(("mail", mail))
+            .await?
+            .check()?;
+        request_mail_change(&info, &connection, "alice@example.org").await?;
+
+        let notices: Vec<String> = info
+            .connection
+            .query("SELECT VALUE recipient FROM mail WHERE type = \"mail_change_notice\"")
+            .await?
+            .take(0)?;
+        assert_eq!(vec![mail.clone()], notices);
+        let links: Vec<String> = info
+            .connection
+            .query("SELECT VALUE link FROM mail WHERE type = \"mail_change_confirmation\" AND recipient = \"alice@example.org\"")
+            .await?
+            .take(0)?;
+        assert_eq!(1, links.len());
+        // nothing changes before the confirmation
+        crate::session::login(&info, fixture::CLIENT, mail, fixture::PASSWORD, None).await?;
+
+        let token = links[0].split("token=").nth(1).unwrap();
+        assert!(confirm_mail_change(&info, "invalid").await.is_err());
+        confirm_mail_change(&info, token).await?;
+        assert!(confirm_mail_change(&info, token).await.is_err());
+
+        assert!(
+            crate::session::login(&info, fixture::CLIENT, mail, fixture::PASSWORD, None)
+                .await
+                .is_err()
+        );
+        crate::session::login(
+            &info,
+            fixture::CLIENT,
+            "alice@example.org",
+            fixture::PASSWORD,
+            None,
+        )
+        .await?;
+        let pending: Vec<String> = info
+            .connection
+            .query(
+                "SELECT VALUE type FROM mail WHERE recipient = \"alice@example.org\" ORDER BY type",
+            )
+            .await?
+            .take(0)?;
+        assert_eq!(vec!["mail_change_confirmation", "password_reset"], pending);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_account_deletion() -> Result<()> {
+        let info = fresh().await?;
+        let alice = fixture::customer(&info, "alice").await?;
+        fixture::customer(&info, "bob").await?;
+        let website = fixture::task(&info, &alice, "Website", "A new company website.").await?;
+        fixture::task_request(&info, &alice, "Online shop", "Selling ceramics online.").await?;
+        let customer = fixture::session(&info, "alice").await?;
+        let other = fixture::session(&info, "bob").await?;
+
+        assert!(request_deletion(&other, &alice).await.is_err());
+        request_deletion(&customer, &alice).await?;
+        assert!(request_deletion(&customer, &alice).await.is_err());
+        cancel_deletion(&customer, &alice).await?;
+        assert!(cancel_deletion(&customer, &alice).await.is_err());
+
+        request_deletion(&customer, &alice).await?;
+        // skip the grace period
+        info.connection
+            .query("UPDATE deletion SET due_at = time::now() WHERE account = account:alice")
+            .await?;
+        process_deletions(&info.connection).await?;
+
+        let mut response = info
+            .connection
+            .query("SELECT * FROM account:alice")
+            .query("SELECT VALUE customer FROM $website")
+            .query("SELECT count() FROM task_request WHERE customer = account:alice GROUP ALL")
+            .query("SELECT VALUE summary FROM deletion WHERE completed_at IS NOT NONE")
+            .query("SELECT count() FROM action_log WHERE author = account:alice OR target = account:alice GROUP ALL")
+            .bind(("website", &website))
+            .await?;
+        assert!(response.take::<Option<serde_json::Value>>(0)?.is_none());
+        assert_eq!(
+            Some(Thing::from(TOMBSTONE)),
+            response.take::<Option<Thing>>(1)?
+        );
+        assert_eq!(None, response.take::<Option<i64>>((2, "count"))?);
+        assert_eq!(1, response.take::<Vec<serde_json::Value>>(3)?.len());
+        assert_eq!(None, response.take::<Option<i64>>((4, "count"))?);
+
+        Ok(())
+    }
+}
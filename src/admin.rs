@@ -0,0 +1,340 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::account::{AccountType, TOMBSTONE};
+use crate::database::ConnectionInfo;
+use crate::permission::authorize;
+use crate::prelude::*;
+use std::str::FromStr;
+use surrealdb::sql::Thing;
+
+/// An account with everything it is allowed to do.
+#[derive(Debug, Clone, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct AccountPermissions {
+    id: Thing,
+    first_name: String,
+    last_name: String,
+    mail: String,
+    #[serde(rename = "type")]
+    ty: AccountType,
+    roles: Vec<Thing>,
+    /// The permissions granted directly or through one of the roles.
+    permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountRow {
+    id: Thing,
+    first_name: String,
+    last_name: String,
+    mail: String,
+    #[serde(rename = "type")]
+    ty: AccountType,
+    roles: Vec<Thing>,
+    permissions: Vec<Thing>,
+}
+
+impl From<AccountRow> for AccountPermissions {
+    fn from(row: AccountRow) -> Self {
+        Self {
+            id: row.id,
+            first_name: row.first_name,
+            last_name: row.last_name,
+            mail: row.mail,
+            ty: row.ty,
+            roles: row.roles,
+            // records of permissions dropped from the catalog are ignored
+            permissions: row
+                .permissions
+                .iter()
+                .filter_map(|permission| Permission::from_str(permission.id.to_raw().as_str()).ok())
+                .collect(),
+        }
+    }
+}
+
+fn parse(permission: &str) -> Result<Permission> {
+    Permission::from_str(permission)
+        .map_err(|_| ApplicationError::UnknownPermission(permission.to_owned()))
+}
+
+/// All accounts with their effective permissions, only visible to admins.
+#[instrument(skip_all)]
+pub async fn accounts(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+) -> Result<Vec<AccountPermissions>> {
+    authorize(connection, Permission::Admin).await?;
+
+    let rows: Vec<AccountRow> = info
+        .connection
+        .statement(
+            "listing account permissions",
+            "SELECT id, first_name, last_name, mail, type,
+                    ->member_of->role.id AS roles,
+                    array::union(
+                        ->has->permission.id,
+                        array::flatten(->member_of->role->grants->permission.id)
+                    ) AS permissions
+                FROM account WHERE id != $tombstone ORDER BY last_name, first_name",
+        )
+        .bind(("tombstone", Thing::from(TOMBSTONE)))
+        .await?
+        .take(0)?;
+
+    Ok(rows.into_iter().map(AccountPermissions::from).collect())
+}
+
+/// Grant a permission of the catalog directly to the given account. The admin connection creates
+/// the relation, so the action log and the notification of the account know who granted it.
+#[instrument(skip(info, connection))]
+pub async fn grant(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+    account: &Thing,
+    permission: &str,
+) -> Result<()> {
+    authorize(connection, Permission::Admin).await?;
+    let permission = parse(permission)?;
+    if granted(info, account, permission).await? {
+        return Err(ApplicationError::BadRequest(format!(
+            "{} already holds {}",
+            account,
+            permission.as_ref()
+        )));
+    }
+
+    connection
+        .statement(
+            "granting permission",
+            "RELATE $account->has->(type::thing(\"permission\", $permission))",
+        )
+        .bind(("account", account))
+        .bind(("permission", permission))
+        .await?;
+
+    Ok(())
+}
+
+/// Revoke a directly granted permission. Permissions of roles stay untouched, and the last
+/// remaining admin is unable to lose the admin permission. Concurrent revocations are refused by
+/// `fn::keep_admin` of the schema.
+#[instrument(skip(info, connection))]
+pub async fn revoke(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+    account: &Thing,
+    permission: &str,
+) -> Result<()> {
+    authorize(connection, Permission::Admin).await?;
+    let permission = parse(permission)?;
+    if !granted(info, account, permission).await? {
+        return Err(ApplicationError::BadRequest(format!(
+            "{} was not granted {} directly",
+            account,
+            permission.as_ref()
+        )));
+    }
+
+    if permission == Permission::Admin && !admin_remains(info, account).await? {
+        return Err(ApplicationError::Forbidden(
+            "Unable to remove the last admin".to_owned(),
+        ));
+    }
+
+    connection
+        .statement(
+            "revoking permission",
+            "DELETE has WHERE in = $account AND out = type::thing(\"permission\", $permission)",
+        )
+        .bind(("account", account))
+        .bind(("permission", permission))
+        .await?;
+
+    Ok(())
+}
+
+/// Lift the lock of an account caused by too many failed signins.
+#[instrument(skip(info, connection))]
+pub async fn unlock(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+    account: &Thing,
+) -> Result<()> {
+    authorize(connection, Permission::Admin).await?;
+
+    info.connection
+        .statement(
+            "unlocking account",
+            "DELETE signin_attempt WHERE mail = $account.mail;
+            UPDATE $account SET locked_until = NONE;",
+        )
+        .bind(("account", account))
+        .await?;
+    info!("Unlocked {}", account);
+
+    Ok(())
+}
+
+/// Whether another account is an admin or the given one stays an admin through its roles.
+async fn admin_remains(info: &ConnectionInfo, account: &Thing) -> Result<bool> {
+    let remains: Option<bool> = info
+        .connection
+        .statement(
+            "checking remaining admins",
+            "LET $admin = type::thing(\"permission\", \"admin\");
+            RETURN array::len(SELECT VALUE id FROM account
+                    WHERE id != $account AND fn::has_permission(id, $admin)) > 0
+                OR $admin INSIDE array::flatten($account->member_of->role->grants->permission.id);",
+        )
+        .bind(("account", account))
+        .await?
+        .take(1)?;
+
+    Ok(remains.unwrap_or_default())
+}
+
+/// Whether the permission is related to the account directly, failing for unknown accounts.
+async fn granted(info: &ConnectionInfo, account: &Thing, permission: Permission) -> Result<bool> {
+    let mut response = info
+        .connection
+        .statement("fetching account", "SELECT VALUE id FROM $account")
+        .query(
+            "SELECT VALUE id FROM has
+                WHERE in = $account AND out = type::thing(\"permission\", $permission)",
+        )
+        .bind(("account", account))
+        .bind(("permission", permission))
+        .await?;
+    let existing: Vec<Thing> = response.take(0)?;
+    if existing.is_empty() || *account == Thing::from(TOMBSTONE) {
+        return Err(ApplicationError::BadRequest(format!(
+            "{} does not exist",
+            account
+        )));
+    }
+
+    Ok(!response.take::<Vec<Thing>>(1)?.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{self, ActionLogType, Filter};
+    use crate::database::fixture::{customer, fresh, owner, session};
+    use crate::permission::has_permission;
+
+    #[tokio::test]
+    async fn test_admin() -> Result<()> {
+        let info = fresh().await?;
+        let staff_id = owner(&info, "staff").await?;
+        let alice_id = customer(&info, "alice").await?;
+        let staff = session(&info, "staff").await?;
+        let alice = session(&info, "alice").await?;
+
+        assert!(matches!(
+            accounts(&info, &alice).await,
+            Err(ApplicationError::Forbidden(_))
+        ));
+        let listed = accounts(&info, &staff).await?;
+        assert_eq!(2, listed.len());
+        let find = |listed: &[AccountPermissions], id: &str| {
+            listed
+                .iter()
+                .find(|account| account.id().id.to_raw() == id)
+                .cloned()
+                .unwrap()
+        };
+        assert!(find(&listed, "staff")
+            .permissions()
+            .contains(&Permission::Admin));
+        assert!(find(&listed, "alice").permissions().is_empty());
+
+        assert!(matches!(
+            grant(&info, &staff, &alice_id, "task.archive").await,
+            Err(ApplicationError::UnknownPermission(_))
+        ));
+        assert!(grant(&info, &alice, &alice_id, "task.select")
+            .await
+            .is_err());
+        grant(&info, &staff, &alice_id, "task.select").await?;
+        assert!(grant(&info, &staff, &alice_id, "task.select")
+            .await
+            .is_err());
+        assert!(has_permission(&alice, Permission::TaskSelect).await?);
+        let listed = accounts(&info, &staff).await?;
+        assert_eq!(
+            &vec![Permission::TaskSelect],
+            find(&listed, "alice").permissions()
+        );
+
+        // the affected account is told about it
+        let notifications: Vec<String> = alice
+            .query("SELECT VALUE type FROM notification WHERE `for` = account:alice")
+            .await?
+            .take(0)?;
+        assert_eq!(vec!["permission_granted".to_owned()], notifications);
+        let granted = audit::logs(
+            &staff,
+            &Filter {
+                author: Some(Thing::from(("account", "staff"))),
+                ty: Some(ActionLogType::PermissionGranted),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert_eq!(1, granted.len());
+
+        revoke(&info, &staff, &alice_id, "task.select").await?;
+        assert!(!has_permission(&alice, Permission::TaskSelect).await?);
+        assert!(revoke(&info, &staff, &alice_id, "task.select")
+            .await
+            .is_err());
+        // permissions of roles are not revoked here
+        assert!(revoke(&info, &staff, &staff_id, "admin").await.is_err());
+
+        // the last admin keeps the admin permission
+        grant(&info, &staff, &alice_id, "admin").await?;
+        info.connection
+            .query("DELETE member_of WHERE in = account:staff")
+            .await?
+            .check()?;
+        assert!(matches!(
+            revoke(&info, &alice, &alice_id, "admin").await,
+            Err(ApplicationError::Forbidden(_))
+        ));
+        assert!(has_permission(&alice, Permission::Admin).await?);
+
+        // neither removing the relation nor the account gets around it
+        assert!(info
+            .connection
+            .query("DELETE has WHERE in = account:alice")
+            .await?
+            .check()
+            .is_err());
+        crate::account::request_deletion(&alice, &alice_id).await?;
+        info.connection
+            .query("UPDATE deletion SET due_at = time::now() WHERE account = account:alice")
+            .await?
+            .check()?;
+        crate::account::process_deletions(&info.connection).await?;
+        assert!(has_permission(&alice, Permission::Admin).await?);
+
+        Ok(())
+    }
+}
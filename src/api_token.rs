@@ -0,0 +1,253 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::database::ConnectionInfo;
+use crate::permission::{authorize, interactive};
+use crate::prelude::*;
+use crate::session::Tokens;
+use rand::distributions::{Alphanumeric, DistString};
+use surrealdb::sql::Thing;
+
+/// Makes the tokens recognizable, e.g. for secret scanners.
+const PREFIX: &str = "yaud_";
+const TOKEN_LENGTH: usize = 48;
+
+/// A personal access token, the token itself is only shown once on creation.
+#[derive(Debug, Clone, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct ApiToken {
+    id: Thing,
+    account: Thing,
+    name: String,
+    permissions: Vec<Thing>,
+    expires_at: Option<String>,
+    last_used_at: Option<String>,
+    created_at: String,
+}
+
+/// Create a personal access token for the signed in account, limited to the given permissions
+/// the account holds itself. Tokens without lifetime, e.g. `90d`, never expire.
+#[instrument(skip(info, connection))]
+pub async fn create(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+    name: &str,
+    permissions: &[Permission],
+    lifetime: Option<&str>,
+) -> Result<(ApiToken, String)> {
+    let account = crate::session::account(connection).await?;
+    if !interactive(connection).await? {
+        return Err(ApplicationError::Forbidden(
+            "API tokens are unable to create further tokens".to_owned(),
+        ));
+    }
+    if name.trim().is_empty() {
+        return Err(ApplicationError::BadRequest(
+            "The token needs a name".to_owned(),
+        ));
+    }
+    for permission in permissions {
+        authorize(connection, *permission).await?;
+    }
+
+    let token = format!(
+        "{PREFIX}{}",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH)
+    );
+    let created: Option<ApiToken> = info
+        .connection
+        .statement(
+            "creating api token",
+            "CREATE ONLY api_token CONTENT {
+                account: $account,
+                name: $name,
+                hash: crypto::sha256($token),
+                permissions: $permissions,
+                expires_at: IF $lifetime IS NONE THEN NONE ELSE time::now() + type::duration($lifetime) END
+            }",
+        )
+        .bind(("account", &account))
+        .bind(("name", name.trim()))
+        .bind(("token", token.as_str()))
+        .bind((
+            "permissions",
+            permissions
+                .iter()
+                .map(|permission| Thing::from(("permission", permission.as_ref())))
+                .collect::<Vec<Thing>>(),
+        ))
+        .bind(("lifetime", lifetime))
+        .await?
+        .take(0)?;
+
+    Ok((created.ok_or(ApplicationError::InternalServerError)?, token))
+}
+
+/// The personal access tokens of the signed in account, the most recent one first.
+pub async fn tokens(connection: &DatabaseConnection) -> Result<Vec<ApiToken>> {
+    Ok(connection
+        .statement(
+            "listing api tokens",
+            "SELECT * FROM api_token ORDER BY created_at DESC",
+        )
+        .await?
+        .take(0)?)
+}
+
+/// Revoke a personal access token of the signed in account, ending the sessions started with it.
+#[instrument(skip(connection))]
+pub async fn revoke(connection: &DatabaseConnection, api_token: &Thing) -> Result<()> {
+    let revoked: Vec<ApiToken> = connection
+        .statement("revoking api token", "DELETE $api_token RETURN BEFORE")
+        .bind(("api_token", api_token))
+        .await?
+        .take(0)?;
+
+    if revoked.is_empty() {
+        return Err(ApplicationError::BadRequest(format!(
+            "The api token {} does not exist",
+            api_token
+        )));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct Used {
+    id: Thing,
+    account: Thing,
+}
+
+/// Exchange a personal access token for the tokens of a session limited to its permissions,
+/// which are used like the ones of an interactive login.
+#[instrument(skip_all)]
+pub async fn authenticate(info: &ConnectionInfo, token: &str) -> Result<Tokens> {
+    let used: Vec<Used> = info
+        .connection
+        .statement(
+            "using api token",
+            "UPDATE api_token SET last_used_at = time::now()
+                WHERE hash = crypto::sha256($token) AND (expires_at IS NONE OR expires_at > time::now())
+                RETURN id, account",
+        )
+        .bind(("token", token))
+        .await?
+        .take(0)?;
+    let used = used.first().ok_or(ApplicationError::Unauthorized)?;
+
+    crate::session::issue(info, &used.account, Some(&used.id)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixture::{customer, fresh, mail, owner, session};
+    use crate::permission::has_permission;
+
+    #[tokio::test]
+    async fn test_api_token() -> Result<()> {
+        let info = fresh().await?;
+        let staff_id = owner(&info, "staff").await?;
+        customer(&info, "alice").await?;
+        let staff = session(&info, "staff").await?;
+        let alice = session(&info, "alice").await?;
+
+        // only permissions held by the account itself can be handed out
+        assert!(
+            create(&info, &alice, "reports", &[Permission::TaskSelect], None)
+                .await
+                .is_err()
+        );
+        assert!(create(&info, &staff, " ", &[Permission::TaskSelect], None)
+            .await
+            .is_err());
+        let (created, token) = create(
+            &info,
+            &staff,
+            "reports",
+            &[Permission::TaskSelect],
+            Some("90d"),
+        )
+        .await?;
+        assert!(token.starts_with(PREFIX));
+        assert!(created.expires_at().is_some());
+        assert!(authenticate(&info, "yaud_invalid").await.is_err());
+
+        let issued = authenticate(&info, token.as_str()).await?;
+        let connection = crate::session::authenticate(&info, issued.access_token.as_str()).await?;
+        assert!(has_permission(&connection, Permission::TaskSelect).await?);
+        assert!(!has_permission(&connection, Permission::TaskEdit).await?);
+        assert!(!has_permission(&connection, Permission::Admin).await?);
+        // the interactive session keeps all of its permissions
+        assert!(has_permission(&staff, Permission::Admin).await?);
+        assert!(
+            create(&info, &connection, "escalated", &[Permission::Admin], None)
+                .await
+                .is_err()
+        );
+        // neither does the token act on the account itself
+        assert!(
+            create(&info, &connection, "copy", &[Permission::TaskSelect], None)
+                .await
+                .is_err()
+        );
+        assert!(crate::export::request(&connection).await.is_err());
+        assert!(crate::account::request_deletion(&connection, &staff_id)
+            .await
+            .is_err());
+        assert!(revoke(&connection, created.id()).await.is_err());
+        connection
+            .query("UPDATE account:staff SET first_name = \"Token\"; DELETE session;")
+            .await?;
+        let first_name: Option<String> = info
+            .connection
+            .query("SELECT VALUE first_name FROM account:staff")
+            .await?
+            .take(0)?;
+        assert_ne!(Some("Token".to_owned()), first_name);
+        assert!(crate::session::sessions(&staff).await?.len() > 1);
+
+        let listed = tokens(&staff).await?;
+        assert_eq!(1, listed.len());
+        assert!(listed[0].last_used_at().is_some());
+        assert!(tokens(&alice).await?.is_empty());
+        assert!(revoke(&alice, created.id()).await.is_err());
+
+        revoke(&staff, created.id()).await?;
+        assert!(authenticate(&info, token.as_str()).await.is_err());
+        assert!(
+            crate::session::authenticate(&info, issued.access_token.as_str())
+                .await
+                .is_err()
+        );
+
+        // a password reset revokes the tokens as well
+        let (_, token) = create(&info, &staff, "reports", &[Permission::TaskSelect], None).await?;
+        crate::account::request_password_reset(&info, &mail("staff")).await?;
+        let links: Vec<String> = info
+            .connection
+            .query("SELECT VALUE link FROM mail WHERE type = \"password_reset\"")
+            .await?
+            .take(0)?;
+        crate::account::reset_password(&info, links[0].split("token=").nth(1).unwrap(), "changed")
+            .await?;
+        assert!(authenticate(&info, token.as_str()).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::prelude::*;
+use surrealdb::sql::Thing;
+
+/// The `ActionType` of the concept, named differently to not collide with the types of
+/// notifications and mails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ActionLogType {
+    Login,
+    Logout,
+    TaskRequestIssued,
+    TaskRequestRevoked,
+    TaskRequestStateChanged,
+    TaskStateChanged,
+    PermissionGranted,
+    PermissionRevoked,
+    RoleAssigned,
+    RoleUnassigned,
+    RolePermissionGranted,
+    RolePermissionRevoked,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct ActionLog {
+    id: Thing,
+    #[serde(rename = "type")]
+    ty: ActionLogType,
+    author: Option<Thing>,
+    target: Thing,
+    permission: Option<Thing>,
+    role: Option<Thing>,
+    created_at: String,
+}
+
+/// The conditions of an audit log query, unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub author: Option<Thing>,
+    pub target: Option<Thing>,
+    pub ty: Option<ActionLogType>,
+    /// Inclusive lower bound of the creation time, e.g. `2023-09-01T00:00:00Z`.
+    pub from: Option<String>,
+    /// Exclusive upper bound of the creation time.
+    pub until: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Append an entry to the audit log. Only root connections are allowed to do so.
+#[instrument(skip(connection))]
+pub async fn record(
+    connection: &DatabaseConnection,
+    ty: ActionLogType,
+    author: Option<&Thing>,
+    target: &Thing,
+) -> Result<()> {
+    connection
+        .statement(
+            "recording action",
+            "CREATE action_log CONTENT { type: $type, author: $author, target: $target }",
+        )
+        .bind(("type", ty))
+        .bind(("author", author))
+        .bind(("target", target))
+        .await?;
+
+    Ok(())
+}
+
+/// Query the audit log, the most recent entries first. Only admins are able to read it.
+#[instrument(skip(connection))]
+pub async fn logs(connection: &DatabaseConnection, filter: &Filter) -> Result<Vec<ActionLog>> {
+    let mut conditions = vec!["true"];
+    if filter.author.is_some() {
+        conditions.push("author = $author");
+    }
+    if filter.target.is_some() {
+        conditions.push("target = $target");
+    }
+    if filter.ty.is_some() {
+        conditions.push("type = $type");
+    }
+    if filter.from.is_some() {
+        conditions.push("created_at >= type::datetime($from)");
+    }
+    if filter.until.is_some() {
+        conditions.push("created_at < type::datetime($until)");
+    }
+
+    Ok(connection
+        .statement(
+            "querying action log",
+            format!(
+                "SELECT * FROM action_log WHERE {} ORDER BY created_at DESC LIMIT $limit",
+                conditions.join(" AND ")
+            ),
+        )
+        .bind(("author", &filter.author))
+        .bind(("target", &filter.target))
+        .bind(("type", filter.ty))
+        .bind(("from", &filter.from))
+        .bind(("until", &filter.until))
+        .bind(("limit", filter.limit.unwrap_or(100)))
+        .await?
+        .take(0)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixture::{customer, fresh, owner, session, task_request};
+    use strum::IntoEnumIterator;
+
+    #[tokio::test]
+    async fn test_action_log() -> Result<()> {
+        let info = fresh().await?;
+        let staff = owner(&info, "staff").await?;
+        let alice = customer(&info, "alice").await?;
+        let bob = customer(&info, "bob").await?;
+        let shop = task_request(&info, &alice, "Online shop", "Selling ceramics online.").await?;
+        let admin = session(&info, "staff").await?;
+        let customer = session(&info, "alice").await?;
+        let owner = Thing::from(("role", "owner"));
+
+        let assigned = logs(
+            &admin,
+            &Filter {
+                ty: Some(ActionLogType::RoleAssigned),
+                target: Some(staff.clone()),
+                ..Default::default()
+            },
+        )
+        .await?;
+        // besides the assistant role every employee starts with
+        assert_eq!(2, assigned.len());
+        assert!(assigned
+            .iter()
+            .any(|log| log.role() == &Some(owner.clone())));
+        let owned = logs(
+            &admin,
+            &Filter {
+                ty: Some(ActionLogType::RolePermissionGranted),
+                target: Some(owner),
+                limit: Some(1000),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert_eq!(Permission::iter().count(), owned.len());
+
+        crate::admin::grant(&info, &admin, &bob, "task.select").await?;
+        let granted = logs(
+            &admin,
+            &Filter {
+                ty: Some(ActionLogType::PermissionGranted),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert!(!granted.is_empty());
+        // permission changes are logged for the affected account
+        assert!(granted
+            .iter()
+            .all(|log| log.target().tb == "account" && log.permission().is_some()));
+
+        let issued = logs(
+            &admin,
+            &Filter {
+                target: Some(shop.clone()),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert_eq!(1, issued.len());
+        assert_eq!(&ActionLogType::TaskRequestIssued, issued[0].ty());
+        assert_eq!(&Some(alice), issued[0].author());
+
+        // the log is neither readable nor writable by anyone else
+        assert!(logs(&customer, &Filter::default()).await?.is_empty());
+        customer
+            .query("CREATE action_log CONTENT { type: \"login\", target: account:staff }")
+            .await?;
+        // the logins of the sessions above are the only ones
+        let logins = logs(
+            &admin,
+            &Filter {
+                ty: Some(ActionLogType::Login),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert_eq!(2, logins.len());
+        assert!(logins.iter().all(|log| log.author().is_some()));
+
+        Ok(())
+    }
+}
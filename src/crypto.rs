@@ -0,0 +1,140 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::prelude::*;
+use crate::CONFIGURATION;
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+/// The length of the keys used with ChaCha20Poly1305.
+pub const KEY_LENGTH: usize = 32;
+
+fn cipher(key: &[u8]) -> Result<ChaCha20Poly1305> {
+    ChaCha20Poly1305::new_from_slice(key).map_err(|_| ApplicationError::CryptoError)
+}
+
+/// The key of the fields only the server is able to decrypt, e.g. the TOTP secrets.
+pub fn server_key() -> Result<Vec<u8>> {
+    let key = STANDARD
+        .decode(CONFIGURATION.secret_key.as_str())
+        .map_err(|_| ApplicationError::CryptoError)?;
+    if key.len() != KEY_LENGTH {
+        error!(
+            "The configured secret key has to be {} bytes long",
+            KEY_LENGTH
+        );
+        return Err(ApplicationError::CryptoError);
+    }
+
+    Ok(key)
+}
+
+/// A random nonce for the first password hash, hex encoded.
+pub fn generate_nonce() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The first argon2 hash of a password, which is the key of the fields encrypted on behalf of
+/// the account. Only the hash of this key gets stored as the password.
+pub fn derive_key(password: &str, nonce: &str) -> Result<Vec<u8>> {
+    let mut key = vec![0u8; KEY_LENGTH];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), nonce.as_bytes(), key.as_mut_slice())
+        .map_err(|_| ApplicationError::CryptoError)?;
+
+    Ok(key)
+}
+
+/// The representation of a derived key handed to the `account` scope as `$password`.
+pub fn encode_key(key: &[u8]) -> String {
+    STANDARD.encode(key)
+}
+
+/// Encrypt the given data with a random iv into the format `iv:base64EncodedData`.
+pub fn encrypt(key: &[u8], data: &[u8]) -> Result<String> {
+    let iv = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let encrypted = cipher(key)?
+        .encrypt(&iv, data)
+        .map_err(|_| ApplicationError::CryptoError)?;
+
+    Ok(format!(
+        "{}:{}",
+        STANDARD.encode(iv),
+        STANDARD.encode(encrypted)
+    ))
+}
+
+/// Decrypt data in the format `iv:base64EncodedData`, failing for tampered data or wrong keys.
+pub fn decrypt(key: &[u8], encrypted: &str) -> Result<Vec<u8>> {
+    let (iv, data) = encrypted
+        .split_once(':')
+        .ok_or(ApplicationError::CryptoError)?;
+    let iv = STANDARD
+        .decode(iv)
+        .map_err(|_| ApplicationError::CryptoError)?;
+    let data = STANDARD
+        .decode(data)
+        .map_err(|_| ApplicationError::CryptoError)?;
+    if iv.len() != 12 {
+        return Err(ApplicationError::CryptoError);
+    }
+
+    cipher(key)?
+        .decrypt(Nonce::from_slice(iv.as_slice()), data.as_slice())
+        .map_err(|_| ApplicationError::CryptoError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption() -> Result<()> {
+        let key = [7u8; KEY_LENGTH];
+        let encrypted = encrypt(&key, b"secret")?;
+
+        assert_eq!(2, encrypted.split(':').count());
+        assert_eq!(b"secret".to_vec(), decrypt(&key, encrypted.as_str())?);
+        // a fresh iv is used every time
+        assert_ne!(encrypted, encrypt(&key, b"secret")?);
+
+        assert!(decrypt(&[8u8; KEY_LENGTH], encrypted.as_str()).is_err());
+        assert!(decrypt(&key, "invalid").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_derive_key() -> Result<()> {
+        let nonce = generate_nonce();
+        assert_eq!(32, nonce.len());
+
+        let key = derive_key("password", nonce.as_str())?;
+        assert_eq!(KEY_LENGTH, key.len());
+        assert_eq!(key, derive_key("password", nonce.as_str())?);
+        assert_ne!(key, derive_key("password", generate_nonce().as_str())?);
+        assert_ne!(key, derive_key("passwort", nonce.as_str())?);
+
+        Ok(())
+    }
+}
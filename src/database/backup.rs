@@ -0,0 +1,179 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::database::schema::{self, Schema};
+use crate::database::{
+    establish, initiate, migrate, migrated_version, pending, target, Authentication, MIGRATIONS, UP,
+};
+use crate::prelude::*;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use surrealdb::sql::Value;
+
+const MAGIC: &str = "yaud backup";
+/// The layout version of the dump, bumped on every incompatible change of it.
+const FORMAT: &str = "1";
+
+/// Every table of the schema plus the migration history.
+fn tables() -> Vec<String> {
+    let mut tables = schema::tables(UP);
+    tables.push("migration".to_owned());
+
+    tables
+}
+
+/// The SurrealQL statement recreating the given record.
+fn statement(table: &str, record: &Value) -> String {
+    match record {
+        // graph edges have to be related again in order to be traversable
+        Value::Object(object) if object.contains_key("in") && object.contains_key("out") => {
+            format!(
+                "RELATE {}->{}->{} CONTENT {};",
+                object["in"], table, object["out"], record
+            )
+        }
+        _ => format!("INSERT INTO {table} {record};"),
+    }
+}
+
+/// Parse the `-- key: value` header at the top of a dump.
+fn header(dump: &str) -> Result<BTreeMap<&str, &str>> {
+    let mut lines = dump.lines();
+    if lines.next() != Some(format!("-- {MAGIC}").as_str()) {
+        return Err(ApplicationError::BadRequest(
+            "The given file is no yaud backup".to_owned(),
+        ));
+    }
+
+    Ok(lines
+        .take_while(|line| line.starts_with("-- "))
+        .filter_map(|line| line[3..].split_once(": "))
+        .collect())
+}
+
+/// Write every record of the selected database into a versioned SurrealQL dump.
+#[instrument(skip(client))]
+pub async fn export(
+    client: &DatabaseConnection,
+    namespace: &str,
+    database: &str,
+    path: &Path,
+) -> Result<()> {
+    // the dump belongs to the schema version of the database, not the one of this binary
+    let version = migrated_version(client)
+        .await?
+        .unwrap_or_else(|| env!("CARGO_PKG_VERSION").to_owned());
+    let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let mut dump = format!(
+        "-- {MAGIC}\n-- format: {FORMAT}\n-- version: {version}\n-- namespace: {namespace}\n-- database: {database}\n-- created_at: {created_at}\n"
+    );
+
+    for table in tables() {
+        let records: Value = client
+            .statement("exporting table", "SELECT * FROM type::table($table)")
+            .bind(("table", table.as_str()))
+            .await?
+            .take(0)?;
+        let Value::Array(records) = records else {
+            continue;
+        };
+
+        dump.push_str(format!("\n-- TABLE: {table}\n").as_str());
+        for record in records.iter() {
+            dump.push_str(statement(table.as_str(), record).as_str());
+            dump.push('\n');
+        }
+        info!("Exported {} record(s) of table {}", records.len(), table);
+    }
+
+    tokio::fs::write(path, dump).await?;
+    info!("Wrote backup of version {} to {:?}", version, path);
+
+    Ok(())
+}
+
+/// Restore a dump into the empty configured database and bring it up to the current version.
+#[instrument]
+pub async fn restore(path: &Path) -> Result<()> {
+    let dump = tokio::fs::read_to_string(path).await?;
+    let header = header(dump.as_str())?;
+
+    if header.get("format") != Some(&FORMAT) {
+        return Err(ApplicationError::BadRequest(format!(
+            "Unsupported backup format {:?}",
+            header.get("format")
+        )));
+    }
+    let version = header
+        .get("version")
+        .ok_or_else(|| ApplicationError::BadRequest("The backup has no version".to_owned()))?;
+    // fails for dumps of newer releases or versions unknown to the registry
+    let migrations = pending(version, env!("CARGO_PKG_VERSION"), MIGRATIONS)?;
+    info!(
+        "Restoring backup of version {}, {} migration(s) pending",
+        version,
+        migrations.len()
+    );
+
+    let (namespace, database) = target(None);
+    let client = establish(namespace.as_str(), database.as_str(), &Authentication::Root).await?;
+    if !Schema::fetch(&client).await?.is_empty() {
+        return Err(ApplicationError::BadRequest(format!(
+            "Refusing to restore into the non-empty database {database:?}"
+        )));
+    }
+
+    // the records are inserted before the schema gets defined, so none of the events fire again
+    client
+        .statement(
+            "restoring backup",
+            format!("BEGIN TRANSACTION;\n{dump}\nCOMMIT TRANSACTION;"),
+        )
+        .await?;
+    initiate(&client).await?;
+    migrate(&client, env!("CARGO_PKG_VERSION"), MIGRATIONS).await?;
+
+    info!("Restored backup into database {database:?} in namespace {namespace:?}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let dump = "-- yaud backup\n-- format: 1\n-- version: 0.1.0\n\n-- TABLE: account\n";
+        let header = header(dump).unwrap();
+
+        assert_eq!(Some(&"1"), header.get("format"));
+        assert_eq!(Some(&"0.1.0"), header.get("version"));
+        assert_eq!(None, header.get("TABLE"));
+        assert!(super::header("DEFINE TABLE account;").is_err());
+    }
+
+    #[test]
+    fn test_tables() {
+        let tables = tables();
+
+        for table in ["account", "has", "task_request", "message", "migration"] {
+            assert!(tables.contains(&table.to_owned()));
+        }
+    }
+}
@@ -0,0 +1,146 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::account::AccountType;
+use crate::database::{connect, ConnectionInfo};
+use crate::prelude::*;
+use surrealdb::sql::Thing;
+
+/// The password of every account created here.
+pub const PASSWORD: &str = "password";
+/// The client address the tests sign in from.
+pub const CLIENT: &str = "127.0.0.1";
+/// The nonce of the first password hash of every account created here.
+const NONCE: &str = "00000000000000000000000000000000";
+
+/// Connect to a new random database inside the test namespace.
+pub async fn fresh() -> Result<ConnectionInfo> {
+    let database = nanoid::nanoid!();
+    connect(Some(("test", database.as_str()))).await
+}
+
+/// The mail address of the account created with the given name.
+pub fn mail(name: &str) -> String {
+    format!("{name}@yaud.test")
+}
+
+/// Create the verified account `account:<name>`.
+async fn account(info: &ConnectionInfo, name: &str, ty: AccountType) -> Result<Thing> {
+    let account = Thing::from(("account", name));
+    let key = crate::crypto::derive_key(PASSWORD, NONCE)?;
+
+    info.connection
+        .query(
+            "CREATE $account CONTENT {
+                first_name: $name,
+                last_name: \"Test\",
+                mail: $mail,
+                type: $type,
+                nonce: $nonce,
+                verified_at: time::now(),
+                password: crypto::argon2::generate($password)
+            }",
+        )
+        .bind(("account", &account))
+        .bind(("name", name))
+        .bind(("mail", mail(name)))
+        .bind(("type", ty))
+        .bind(("nonce", NONCE))
+        .bind(("password", crate::crypto::encode_key(&key)))
+        .await?
+        .check()?;
+
+    Ok(account)
+}
+
+/// Create a customer without any permissions.
+pub async fn customer(info: &ConnectionInfo, name: &str) -> Result<Thing> {
+    account(info, name, AccountType::Customer).await
+}
+
+/// Create an employee allowed to do everything through the owner role.
+pub async fn owner(info: &ConnectionInfo, name: &str) -> Result<Thing> {
+    let account = account(info, name, AccountType::Employee).await?;
+
+    info.connection
+        .query("RELATE $account->member_of->role:owner")
+        .bind(("account", &account))
+        .await?
+        .check()?;
+
+    Ok(account)
+}
+
+/// Sign in as the account created with the given name.
+pub async fn session(info: &ConnectionInfo, name: &str) -> Result<DatabaseConnection> {
+    let tokens = crate::session::login(info, CLIENT, mail(name).as_str(), PASSWORD, None).await?;
+
+    crate::session::authenticate(info, tokens.access_token.as_str()).await
+}
+
+/// Open a request in the name of the given customer.
+pub async fn task_request(
+    info: &ConnectionInfo,
+    customer: &Thing,
+    title: &str,
+    description: &str,
+) -> Result<Thing> {
+    let id: Option<Thing> = info
+        .connection
+        .query(
+            "CREATE ONLY task_request CONTENT {
+                title: $title,
+                description: $description,
+                customer: $customer
+            } RETURN VALUE id",
+        )
+        .bind(("title", title))
+        .bind(("description", description))
+        .bind(("customer", customer))
+        .await?
+        .take(0)?;
+
+    id.ok_or(ApplicationError::InternalServerError)
+}
+
+/// Create a task of the given customer.
+pub async fn task(
+    info: &ConnectionInfo,
+    customer: &Thing,
+    title: &str,
+    description: &str,
+) -> Result<Thing> {
+    let id: Option<Thing> = info
+        .connection
+        .query(
+            "CREATE ONLY task CONTENT {
+                title: $title,
+                description: $description,
+                customer: $customer,
+                due: time::now() + 1w,
+                state: \"open\",
+                priority: \"medium\"
+            } RETURN VALUE id",
+        )
+        .bind(("title", title))
+        .bind(("description", description))
+        .bind(("customer", customer))
+        .await?
+        .take(0)?;
+
+    id.ok_or(ApplicationError::InternalServerError)
+}
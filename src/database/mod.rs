@@ -17,15 +17,45 @@
 
 use crate::prelude::*;
 
+use crate::permission::Permission;
 use crate::CONFIGURATION;
 use surrealdb::engine::remote::ws::{Client, Ws};
 use surrealdb::opt::auth::Root;
 use surrealdb::Surreal;
-#[cfg(not(test))]
 use version_compare::{Cmp, Version};
 
+pub mod backup;
+/// The records the tests rely on. Every test creates exactly what it needs on a fresh database
+/// instead of depending on the demo data of the seed.
+#[cfg(test)]
+pub mod fixture;
+pub mod schema;
+pub mod seed;
+pub mod statement;
+pub mod supervisor;
+
 pub type DatabaseConnection = Surreal<Client>;
 
+/// The schema definitions executed on every startup.
+pub const UP: &str = include_str!("../up.surrealql");
+/// The definitions of the table holding the migration history.
+pub const MIGRATION_TABLE: &str = "DEFINE TABLE migration SCHEMALESS;
+    DEFINE FIELD version     on TABLE migration TYPE string;
+    DEFINE FIELD created_at  on TABLE migration TYPE datetime DEFAULT time::now();";
+/// The registry of every schema migration as pairs of the version and its query, in ascending
+/// order. Migrations run after the schema was applied, so they are able to fill its new fields.
+pub const MIGRATIONS: &[(&str, &str)] = &[("0.2.0", include_str!("./migrations/0.2.0.surrealql"))];
+
+/// The schema including the definitions generated from the rust side.
+pub fn up() -> String {
+    format!(
+        "{}\n{}\n{}",
+        Permission::define(),
+        UP,
+        crate::session::define()
+    )
+}
+
 #[derive(Debug, Clone)]
 pub struct ConnectionInfo {
     pub connection: DatabaseConnection,
@@ -33,52 +63,70 @@ pub struct ConnectionInfo {
     pub namespace: String,
 }
 
-pub async fn connect(options: Option<(&str, &str)>) -> Result<ConnectionInfo> {
+/// The way a connection authenticates itself against surrealdb.
+#[derive(Debug, Clone)]
+pub enum Authentication {
+    Root,
+    /// A signed access token, see [crate::session].
+    Token(String),
+}
+
+/// Open a new authenticated session on the given database without touching the schema.
+pub async fn establish(
+    namespace: &str,
+    database: &str,
+    authentication: &Authentication,
+) -> Result<DatabaseConnection> {
     // establish the connection
     let client: Surreal<Client> = Surreal::new::<Ws>(&CONFIGURATION.surrealdb_endpoint).await?;
     info!("Established connection to surrealdb");
 
     // authenticate
-    client
-        .signin(Root {
-            username: CONFIGURATION.surrealdb_username.as_str(),
-            password: CONFIGURATION.surrealdb_password.as_str(),
-        })
-        .await?;
+    match authentication {
+        Authentication::Root => {
+            client
+                .signin(Root {
+                    username: CONFIGURATION.surrealdb_username.as_str(),
+                    password: CONFIGURATION.surrealdb_password.as_str(),
+                })
+                .await?;
+        }
+        Authentication::Token(token) => {
+            client.authenticate(token.as_str()).await?;
+        }
+    }
     info!("Authenticated with surrealdb");
 
-    #[cfg(not(test))]
-    let database = "yaud".to_owned();
-    #[cfg(not(test))]
-    let namespace = "production".to_owned();
+    client.use_ns(namespace).use_db(database).await?;
+    info!("Using database {database:?} in namespace {namespace:?}");
 
-    #[cfg(test)]
-    let (namespace, database) = if let Some(options) = options {
-        (options.0.to_string(), options.1.to_string())
-    } else {
-        ("test".to_owned(), nanoid::nanoid!())
-    };
+    Ok(client)
+}
 
-    #[cfg(test)]
-    println!(
-        "Connected with database {:?} in namespace \"test\"",
-        database
-    );
+/// The namespace and database to use, defaulting to the configured instance.
+pub fn target(options: Option<(&str, &str)>) -> (String, String) {
+    match options {
+        Some((namespace, database)) => (namespace.to_owned(), database.to_owned()),
+        None => (
+            CONFIGURATION.surrealdb_namespace.clone(),
+            CONFIGURATION.surrealdb_database.clone(),
+        ),
+    }
+}
 
-    client
-        .use_ns(namespace.as_str())
-        .use_db(database.as_str())
-        .await?;
+pub async fn connect(options: Option<(&str, &str)>) -> Result<ConnectionInfo> {
+    // refuse to start with a schema referencing unknown permissions
+    crate::permission::validate(UP)?;
 
+    let (namespace, database) = target(options);
+    let client = establish(namespace.as_str(), database.as_str(), &Authentication::Root).await?;
+
+    initiate(&client).await?;
     // perform the migrations
-    #[cfg(not(test))]
-    migrate(&client, env!("CARGO_PKG_VERSION"), Vec::new()).await?;
-    // execute the up queries
-    client
-        .query(include_str!("./up.surrealql"))
-        .await?
-        .check()?;
-    info!("Initiated tables");
+    migrate(&client, env!("CARGO_PKG_VERSION"), MIGRATIONS).await?;
+    // the schema overwrites its own definitions, whatever is left over was defined elsewhere
+    let drift = schema::check(&client, namespace.as_str(), database.as_str()).await?;
+    schema::report(&drift, CONFIGURATION.schema_strict)?;
 
     Ok(ConnectionInfo {
         database,
@@ -87,74 +135,111 @@ pub async fn connect(options: Option<(&str, &str)>) -> Result<ConnectionInfo> {
     })
 }
 
-#[cfg(not(test))]
+/// Execute the up queries.
+pub async fn initiate(client: &DatabaseConnection) -> Result<()> {
+    client.statement("defining schema", up()).await?;
+    info!("Initiated tables");
+
+    Ok(())
+}
+
+fn version(version: &str) -> Result<Version> {
+    Version::from(version)
+        .ok_or_else(|| ApplicationError::BadRequest(format!("Invalid version {version:?}")))
+}
+
+/// The last version the database was migrated to.
+pub async fn migrated_version(client: &DatabaseConnection) -> Result<Option<String>> {
+    Ok(client
+        .statement(
+            "fetching migrated version",
+            "SELECT version, created_at FROM migration ORDER BY created_at DESC LIMIT 1",
+        )
+        .await?
+        .take::<Option<String>>((0, "version"))?)
+}
+
+/// The migrations of the registry required to get from one version to another.
+pub fn pending(
+    from: &str,
+    to: &str,
+    migrations: &'static [(&'static str, &'static str)],
+) -> Result<Vec<(&'static str, &'static str)>> {
+    let (from, to) = (version(from)?, version(to)?);
+    if from.compare_to(&to, Cmp::Gt) {
+        return Err(ApplicationError::BadRequest(format!(
+            "Unable to migrate from the newer version {from} to {to}"
+        )));
+    }
+
+    let mut pending = Vec::new();
+    for (version, migration) in migrations {
+        let parsed = self::version(version)?;
+
+        if parsed.compare_to(&from, Cmp::Gt) && parsed.compare_to(&to, Cmp::Le) {
+            pending.push((*version, *migration));
+        }
+    }
+
+    Ok(pending)
+}
+
 pub async fn migrate(
     client: &DatabaseConnection,
     current_version: &'static str,
-    migrations: Vec<(&'static str, &'static str)>,
+    migrations: &'static [(&'static str, &'static str)],
 ) -> Result<()> {
     // initiate the migration table and fetch possibly already existing records
-    let mut responses = client
-        .query(
-            "DEFINE TABLE migration SCHEMALESS;
-            DEFINE FIELD version     on TABLE migration TYPE string;
-            DEFINE FIELD created_at  on TABLE migration TYPE datetime DEFAULT time::now();",
-        )
-        .query("SELECT version, created_at FROM migration ORDER BY created_at DESC LIMIT 1")
-        .await?
-        .check()?;
-    // take the last as response, which contains the last migrated version
-    let last = responses.take::<Option<String>>((1, "version"))?;
+    client
+        .statement("defining migration table", MIGRATION_TABLE)
+        .await?;
+    let last = migrated_version(client).await?;
 
     if let Some(last) = last {
         // only proceed if the  last version is not equal to the current version
         if !last.as_str().eq(current_version) {
-            // iterate through the given migrations
-            for (version, migration) in migrations {
-                if Version::from(last.as_str())
-                    .unwrap()
-                    .compare_to(Version::from(current_version).unwrap(), Cmp::Lt)
-                {
-                    info!("Executing surrealdb migration to {version}");
-                    // execute the migration query and mark it as done
-                    client
-                        .query(migration)
-                        .query("CREATE migration SET version = $version")
-                        .bind(("version", version))
-                        .await?
-                        .check()?;
-                }
+            let mut migrated = last;
+
+            for (version, migration) in pending(migrated.as_str(), current_version, migrations)? {
+                info!("Executing surrealdb migration to {version}");
+                // execute the migration query and mark it as done
+                client
+                    .statement("executing migration", migration)
+                    .query("CREATE migration SET version = $version")
+                    .bind(("version", version))
+                    .await?;
+                migrated = version.to_owned();
+            }
+
+            // versions without migrations have to be recorded as well
+            if !migrated.as_str().eq(current_version) {
+                client
+                    .statement(
+                        "recording migrated version",
+                        "CREATE migration SET version = $version",
+                    )
+                    .bind(("version", current_version))
+                    .await?;
             }
         }
     } else {
         // insert the current version as the last version
         client
-            .query("CREATE migration SET version = $version")
+            .statement(
+                "recording migrated version",
+                "CREATE migration SET version = $version",
+            )
             .bind(("version", current_version))
-            .await?
-            .check()?;
+            .await?;
     }
 
     Ok(())
 }
 
-#[macro_export]
-macro_rules! sql_span {
-    ($expr: expr) => {{
-        let span = info_span!("Surrealdb Request");
-        let _ = span.enter();
-        $expr
-    }};
-    ($expr: expr, $title: expr) => {{
-        let span = info_span!(concat!("Surrealdb Request: ", $title));
-        let _ = span.enter();
-        $expr
-    }};
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::database::fixture::{self, fresh};
     use chrono::Utc;
     use lazy_static::lazy_static;
     use std::ops::Deref;
@@ -162,6 +247,10 @@ mod tests {
     use surrealdb::opt::auth::Scope;
     use surrealdb::sql::Thing;
 
+    /// The nonce of the accounts signed up by the tests, which pass their passwords as already
+    /// derived keys.
+    const NONCE: &str = "00000000000000000000000000000000";
+
     lazy_static! {
         pub static ref TEST_MAIL: String = std::env::var("TEST_MAIL").unwrap();
         pub static ref TEST_MAIL2: String = std::env::var("TEST_MAIL2").unwrap();
@@ -176,6 +265,19 @@ mod tests {
         Ok(info.connection)
     }
 
+    /// Mark the account of the given mail as verified, as the tests are unable to follow the link
+    /// of the verification mail.
+    async fn verify(info: &ConnectionInfo, mail: &str) -> Result<()> {
+        root(info)
+            .await?
+            .query("UPDATE account SET verified_at = time::now() WHERE mail = $mail")
+            .bind(("mail", mail))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
     async fn wait_for_mail(from: i64, mail: &str) -> Result<()> {
         let tag = mail.split(".").nth(1).unwrap().split("@").next().unwrap();
 
@@ -199,8 +301,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_signup() -> Result<()> {
-        let info = connect(None).await?;
+        let info = fresh().await?;
         let connection = &info.connection;
+        let key = crate::crypto::derive_key("password", NONCE)?;
 
         connection
             .signup(Scope {
@@ -211,23 +314,20 @@ mod tests {
                     "first": "first",
                     "last": "last",
                     "mail": TEST_MAIL.as_str(),
-                    "password": "password"
-                }),
-            })
-            .await?;
-
-        connection
-            .signin(Scope {
-                namespace: info.namespace.as_str(),
-                database: info.database.as_str(),
-                scope: "account",
-                params: &json!({
-                    "mail": TEST_MAIL.as_str(),
-                    "password": "password"
+                    "nonce": NONCE,
+                    "password": crate::crypto::encode_key(&key)
                 }),
             })
             .await?;
 
+        let client = fixture::CLIENT;
+        crate::session::login(&info, client, TEST_MAIL.as_str(), "password", None).await?;
+        assert!(
+            crate::session::login(&info, client, TEST_MAIL.as_str(), "passwrd", None)
+                .await
+                .is_err()
+        );
+        // the scope itself refuses every signin, as it would not count the failed ones
         assert!(connection
             .signin(Scope {
                 namespace: info.namespace.as_str(),
@@ -235,7 +335,7 @@ mod tests {
                 scope: "account",
                 params: &json!({
                     "mail": TEST_MAIL.as_str(),
-                    "password": "passwrd"
+                    "password": "password"
                 }),
             })
             .await
@@ -257,16 +357,16 @@ mod tests {
                     "first": "first",
                     "last": "last",
                     "mail": *TEST_MAIL,
+                    "nonce": NONCE,
                     "password": "password"
                 }),
             })
             .await?;
+        verify(info, TEST_MAIL.as_str()).await?;
 
         root.query(
             "LET $account = SELECT * FROM account WHERE mail = $mail;\
-                 FOR $permission IN $permissions {\
-                    RELATE $account->has->(type::thing(\"permission\", $permission));
-                 };",
+                 RELATE $account->member_of->role:owner;",
         )
         .bind(("mail", TEST_MAIL.as_str()))
         .await?
@@ -289,11 +389,14 @@ mod tests {
                     "first": "second",
                     "last": "last",
                     "mail": TEST_MAIL2.as_str(),
+                    "nonce": NONCE,
                     "password": "password"
                 }),
             })
             .await?;
 
+        verify(info, TEST_MAIL2.as_str()).await?;
+
         Ok(connection)
     }
 
@@ -329,7 +432,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_account_update() -> Result<()> {
-        let connection = init(&connect(None).await?).await?;
+        let connection = init(&fresh().await?).await?;
         let account = fetch_account(&connection).await?;
 
         let updated: Account = connection
@@ -354,13 +457,15 @@ mod tests {
         assert_eq!(true, updated.options.notify_state_updated);
         assert_eq!(true, updated.options.notify_message_created);
 
-        assert!(connection
+        // the mail address is only changed through the confirmation flow
+        let updated = connection
             .update::<Option<Account>>(account.id.clone())
             .merge(&json! ({
-                "mail": "test"
+                "mail": "changed@yaud.example"
             }))
-            .await
-            .is_err());
+            .await?
+            .unwrap();
+        assert_eq!(account.mail, updated.mail);
 
         Ok(())
     }
@@ -386,9 +491,38 @@ mod tests {
         Ok(request.first().unwrap().clone())
     }
 
+    #[tokio::test]
+    async fn test_migrations() -> Result<()> {
+        let info = fresh().await?;
+        info.connection
+            .query("CREATE account:legacy CONTENT { first_name: \"Legacy\", last_name: \"Account\", mail: \"legacy@yaud.example\", password: \"\" }")
+            .query("CREATE account:former CONTENT { first_name: \"Former\", last_name: \"Staff\", mail: \"former@yaud.example\", password: \"\" }")
+            .query("LET $permission = type::thing(\"permission\", \"task.select\"); RELATE account:former->has->$permission")
+            .await?
+            .check()?;
+
+        for (_, migration) in MIGRATIONS {
+            info.connection.query(*migration).await?.check()?;
+        }
+        let verified: Option<bool> = info
+            .connection
+            .query("RETURN account:legacy.verified_at IS NOT NONE")
+            .await?
+            .take(0)?;
+        assert_eq!(Some(true), verified);
+        let types: Vec<String> = info
+            .connection
+            .query("SELECT VALUE type FROM [account:legacy, account:former]")
+            .await?
+            .take(0)?;
+        assert_eq!(vec!["customer", "employee"], types);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_task_request_creation() -> Result<()> {
-        let info = connect(None).await?;
+        let info = fresh().await?;
         let admin = init(&info).await?;
         let client = second(&info).await?;
         let root = root(&info).await?;
@@ -409,6 +543,12 @@ mod tests {
         create_task_request(&client).await?;
         crate::hook::mail::mail_hook(&root).await?;
         assert!(wait_for_mail(from, TEST_MAIL.as_str()).await.is_ok());
+        // the links of delivered mails are not kept
+        let links: Vec<Option<String>> = root
+            .query("SELECT VALUE link FROM mail WHERE state = \"delivered\"")
+            .await?
+            .take(0)?;
+        assert!(!links.is_empty() && links.iter().all(Option::is_none));
 
         Ok(())
     }
@@ -424,7 +564,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_send_message() -> Result<()> {
-        let info = connect(None).await?;
+        let info = fresh().await?;
         let client = second(&info).await?;
         let admin = init(&info).await?;
 
@@ -0,0 +1,498 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::database::{establish, up, Authentication, MIGRATION_TABLE, UP};
+use crate::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// Suffix of the temporary database the expected schema gets applied to.
+const SCRATCH_SUFFIX: &str = "_schema_check";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum DefinitionKind {
+    Login,
+    Token,
+    Function,
+    Param,
+    Scope,
+    Analyzer,
+    Table,
+    Event,
+    Field,
+    View,
+    Index,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Drift {
+    Missing {
+        kind: DefinitionKind,
+        name: String,
+    },
+    Unexpected {
+        kind: DefinitionKind,
+        name: String,
+    },
+    Changed {
+        kind: DefinitionKind,
+        name: String,
+        expected: String,
+        found: String,
+    },
+    Misplaced {
+        field: String,
+        table: String,
+        block: String,
+    },
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Drift::Missing { kind, name } => write!(
+                f,
+                "{} {} is defined in the schema but missing in the database",
+                kind.as_ref(),
+                name
+            ),
+            Drift::Unexpected { kind, name } => write!(
+                f,
+                "{} {} exists in the database but is not part of the schema",
+                kind.as_ref(),
+                name
+            ),
+            Drift::Changed {
+                kind,
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} {} differs from the schema: expected `{}`, found `{}`",
+                kind.as_ref(),
+                name,
+                expected,
+                found
+            ),
+            Drift::Misplaced {
+                field,
+                table,
+                block,
+            } => write!(
+                f,
+                "field {} is declared on table {} inside the definitions of table {}",
+                field, table, block
+            ),
+        }
+    }
+}
+
+type Definitions = BTreeMap<String, String>;
+
+/// The response of `INFO FOR DB`.
+#[derive(Debug, Default, Deserialize)]
+struct DatabaseInfo {
+    #[serde(default)]
+    dl: Definitions,
+    #[serde(default)]
+    dt: Definitions,
+    #[serde(default)]
+    fc: Definitions,
+    #[serde(default)]
+    pa: Definitions,
+    #[serde(default)]
+    sc: Definitions,
+    #[serde(default)]
+    tb: Definitions,
+    #[serde(default)]
+    az: Definitions,
+}
+
+/// The response of `INFO FOR TABLE`.
+#[derive(Debug, Default, Deserialize)]
+struct TableInfo {
+    #[serde(default)]
+    ev: Definitions,
+    #[serde(default)]
+    fd: Definitions,
+    #[serde(default)]
+    ft: Definitions,
+    #[serde(default)]
+    ix: Definitions,
+}
+
+#[derive(Debug, Default)]
+pub struct Schema {
+    database: DatabaseInfo,
+    tables: BTreeMap<String, TableInfo>,
+}
+
+impl Schema {
+    /// Read the definitions of the currently selected database.
+    pub async fn fetch(connection: &DatabaseConnection) -> Result<Self> {
+        let database: Option<DatabaseInfo> = connection
+            .statement("fetching database definitions", "INFO FOR DB")
+            .await?
+            .take(0)?;
+        let database = database.unwrap_or_default();
+
+        let mut tables = BTreeMap::new();
+        if !database.tb.is_empty() {
+            let query = database
+                .tb
+                .keys()
+                .map(|table| format!("INFO FOR TABLE `{table}`;"))
+                .collect::<String>();
+            let mut responses = connection
+                .statement("fetching table definitions", query)
+                .await?;
+
+            for (index, table) in database.tb.keys().enumerate() {
+                let info: Option<TableInfo> = responses.take(index)?;
+                tables.insert(table.clone(), info.unwrap_or_default());
+            }
+        }
+
+        Ok(Self { database, tables })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.database.tb.is_empty()
+    }
+
+    /// A database is considered fresh if nothing but the migration history was defined yet.
+    pub fn is_fresh(&self) -> bool {
+        self.database.tb.keys().all(|table| table == "migration")
+    }
+
+    /// Collect every difference between the expected schema (`self`) and the found one.
+    pub fn compare(&self, found: &Schema) -> Vec<Drift> {
+        let mut drift = Vec::new();
+        let (expected, live) = (&self.database, &found.database);
+
+        compare(
+            DefinitionKind::Login,
+            None,
+            &expected.dl,
+            &live.dl,
+            &mut drift,
+        );
+        compare(
+            DefinitionKind::Token,
+            None,
+            &expected.dt,
+            &live.dt,
+            &mut drift,
+        );
+        compare(
+            DefinitionKind::Function,
+            None,
+            &expected.fc,
+            &live.fc,
+            &mut drift,
+        );
+        compare(
+            DefinitionKind::Param,
+            None,
+            &expected.pa,
+            &live.pa,
+            &mut drift,
+        );
+        compare(
+            DefinitionKind::Scope,
+            None,
+            &expected.sc,
+            &live.sc,
+            &mut drift,
+        );
+        compare(
+            DefinitionKind::Analyzer,
+            None,
+            &expected.az,
+            &live.az,
+            &mut drift,
+        );
+        compare(
+            DefinitionKind::Table,
+            None,
+            &expected.tb,
+            &live.tb,
+            &mut drift,
+        );
+
+        let empty = TableInfo::default();
+        for (table, expected) in self.tables.iter() {
+            // missing tables are already reported above
+            let Some(live) = found.tables.get(table) else {
+                continue;
+            };
+            let scope = Some(table.as_str());
+
+            compare(
+                DefinitionKind::Event,
+                scope,
+                &expected.ev,
+                &live.ev,
+                &mut drift,
+            );
+            compare(
+                DefinitionKind::Field,
+                scope,
+                &expected.fd,
+                &live.fd,
+                &mut drift,
+            );
+            compare(
+                DefinitionKind::View,
+                scope,
+                &expected.ft,
+                &live.ft,
+                &mut drift,
+            );
+            compare(
+                DefinitionKind::Index,
+                scope,
+                &expected.ix,
+                &live.ix,
+                &mut drift,
+            );
+        }
+        // report the contents of unexpected tables as well
+        for (table, live) in found.tables.iter() {
+            if self.tables.contains_key(table) {
+                continue;
+            }
+            let scope = Some(table.as_str());
+
+            compare(
+                DefinitionKind::Event,
+                scope,
+                &empty.ev,
+                &live.ev,
+                &mut drift,
+            );
+            compare(
+                DefinitionKind::Field,
+                scope,
+                &empty.fd,
+                &live.fd,
+                &mut drift,
+            );
+            compare(
+                DefinitionKind::Index,
+                scope,
+                &empty.ix,
+                &live.ix,
+                &mut drift,
+            );
+        }
+
+        drift
+    }
+}
+
+fn compare(
+    kind: DefinitionKind,
+    scope: Option<&str>,
+    expected: &Definitions,
+    found: &Definitions,
+    drift: &mut Vec<Drift>,
+) {
+    let name = |key: &String| match scope {
+        Some(scope) => format!("{scope}.{key}"),
+        None => key.clone(),
+    };
+
+    for (key, definition) in expected.iter() {
+        match found.get(key) {
+            None => drift.push(Drift::Missing {
+                kind,
+                name: name(key),
+            }),
+            Some(live) if live != definition => drift.push(Drift::Changed {
+                kind,
+                name: name(key),
+                expected: definition.clone(),
+                found: live.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    for key in found.keys().filter(|key| !expected.contains_key(*key)) {
+        drift.push(Drift::Unexpected {
+            kind,
+            name: name(key),
+        });
+    }
+}
+
+/// Find field definitions that are placed inside the block of another table. Such fields are
+/// valid SurrealQL, but never what was intended.
+pub fn lint(source: &str) -> Vec<Drift> {
+    let mut drift = Vec::new();
+    let mut block: Option<String> = None;
+
+    for line in source.lines() {
+        let indented = line.starts_with(char::is_whitespace);
+        let tokens = line.split_whitespace().collect::<Vec<&str>>();
+
+        match tokens.as_slice() {
+            [define, table, name, ..]
+                if define.eq_ignore_ascii_case("DEFINE") && table.eq_ignore_ascii_case("TABLE") =>
+            {
+                block = Some(name.trim_end_matches(';').to_owned())
+            }
+            [define, field, name, on, table, target, ..]
+                if indented
+                    && define.eq_ignore_ascii_case("DEFINE")
+                    && field.eq_ignore_ascii_case("FIELD")
+                    && on.eq_ignore_ascii_case("ON")
+                    && table.eq_ignore_ascii_case("TABLE") =>
+            {
+                if let Some(block) = block.as_ref() {
+                    if !block.eq(target) {
+                        drift.push(Drift::Misplaced {
+                            field: name.to_string(),
+                            table: target.to_string(),
+                            block: block.clone(),
+                        });
+                    }
+                }
+            }
+            [define, ..] if !indented && define.eq_ignore_ascii_case("DEFINE") => block = None,
+            _ => {}
+        }
+    }
+
+    drift
+}
+
+/// The names of every table defined in the given schema.
+pub fn tables(source: &str) -> Vec<String> {
+    source
+        .lines()
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                [define, table, name, ..]
+                    if define.eq_ignore_ascii_case("DEFINE")
+                        && table.eq_ignore_ascii_case("TABLE") =>
+                {
+                    Some(name.trim_end_matches(';').to_owned())
+                }
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+/// Apply the schema to a temporary database next to the given one and read back the resulting
+/// definitions, so they are normalized exactly like the live ones. It uses a connection of its
+/// own, the shared one never leaves the live database.
+async fn expected(namespace: &str, database: &str) -> Result<Schema> {
+    let scratch = format!("{database}{SCRATCH_SUFFIX}");
+    let connection = establish(namespace, scratch.as_str(), &Authentication::Root).await?;
+
+    let schema = async {
+        connection
+            .statement("defining expected schema", MIGRATION_TABLE)
+            .query(up())
+            .await?;
+        Schema::fetch(&connection).await
+    }
+    .await;
+
+    // always clean up, even if applying the schema failed
+    connection
+        .statement(
+            "removing expected schema",
+            format!("REMOVE DATABASE `{scratch}`"),
+        )
+        .await?;
+
+    schema
+}
+
+/// Compare the live definitions of the selected database against the schema.
+#[instrument(skip(connection))]
+pub async fn check(
+    connection: &DatabaseConnection,
+    namespace: &str,
+    database: &str,
+) -> Result<Vec<Drift>> {
+    let mut drift = lint(UP);
+
+    let found = Schema::fetch(connection).await?;
+    // there is nothing to drift from on a new database
+    if found.is_fresh() {
+        return Ok(drift);
+    }
+
+    let expected = expected(namespace, database).await?;
+    drift.extend(expected.compare(&found));
+
+    Ok(drift)
+}
+
+/// Log the detected drift and fail in strict mode.
+pub fn report(drift: &[Drift], strict: bool) -> Result<()> {
+    if drift.is_empty() {
+        info!("Schema matches the database definitions");
+        return Ok(());
+    }
+
+    for entry in drift {
+        warn!("Schema drift: {}", entry);
+    }
+
+    if strict {
+        return Err(ApplicationError::SchemaDrift(drift.len()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_schema() {
+        assert_eq!(Vec::<Drift>::new(), lint(UP));
+    }
+
+    #[test]
+    fn test_lint_misplaced_field() {
+        let source = "DEFINE TABLE message SCHEMAFULL;
+    DEFINE FIELD content    on TABLE message TYPE string;
+    DEFINE FIELD created_at on TABLE task TYPE datetime;
+
+DEFINE TABLE task SCHEMAFULL;
+    DEFINE FIELD created_at on TABLE task TYPE datetime;";
+
+        assert_eq!(
+            vec![Drift::Misplaced {
+                field: "created_at".to_owned(),
+                table: "task".to_owned(),
+                block: "message".to_owned(),
+            }],
+            lint(source)
+        );
+    }
+}
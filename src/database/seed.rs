@@ -0,0 +1,89 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::prelude::*;
+
+/// The password of every seeded account.
+const DEMO_PASSWORD: &str = "password";
+/// The nonce of the first password hash of every seeded account.
+const DEMO_NONCE: &str = "00000000000000000000000000000000";
+const SEED: &str = include_str!("./seed.surrealql");
+
+/// Fill the selected database with demo data for development and presentations.
+#[instrument(skip_all)]
+pub async fn seed(connection: &DatabaseConnection) -> Result<()> {
+    // never mix demo data into real data
+    let accounts: Option<i64> = connection
+        .statement(
+            "counting accounts",
+            "SELECT count() FROM account WHERE id != account:tombstone GROUP ALL",
+        )
+        .await?
+        .take((0, "count"))?;
+    if accounts.unwrap_or_default() > 0 {
+        return Err(ApplicationError::BadRequest(
+            "Refusing to seed a database which already contains accounts".to_owned(),
+        ));
+    }
+
+    let key = crate::crypto::derive_key(DEMO_PASSWORD, DEMO_NONCE)?;
+    connection
+        .statement("seeding demo data", SEED)
+        .bind(("password", crate::crypto::encode_key(&key)))
+        .bind(("nonce", DEMO_NONCE))
+        .await?;
+    // the events queued mails to the undeliverable demo addresses
+    connection
+        .statement("discarding demo mails", "DELETE mail")
+        .query("UPDATE hook SET pending = false")
+        .await?;
+
+    info!(
+        "Seeded demo data, sign in as staff@yaud.example with the password {:?}",
+        DEMO_PASSWORD
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixture::fresh;
+
+    #[tokio::test]
+    async fn test_seed() -> Result<()> {
+        let connection = fresh().await?.connection;
+        seed(&connection).await?;
+        assert!(seed(&connection).await.is_err());
+
+        let states: Vec<String> = connection
+            .query("SELECT VALUE state FROM task_request")
+            .await?
+            .take(0)?;
+        for state in ["received", "evaluation", "accepted", "rejected"] {
+            assert!(states.contains(&state.to_owned()));
+        }
+
+        let mails: Option<i64> = connection
+            .query("SELECT count() FROM mail GROUP ALL")
+            .await?
+            .take((0, "count"))?;
+        assert_eq!(None, mails);
+
+        Ok(())
+    }
+}
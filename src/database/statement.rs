@@ -0,0 +1,166 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::prelude::*;
+use crate::CONFIGURATION;
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use surrealdb::engine::remote::ws::Client;
+use surrealdb::method::Query;
+use surrealdb::opt::{IntoQuery, QueryResult};
+use surrealdb::sql::Value;
+use surrealdb::Response;
+use tracing::field::{display, Empty};
+use tracing::{Instrument, Span};
+
+pub trait Statement {
+    /// Start a named query, which gets executed within its own span recording the bound
+    /// parameter names, the duration, the amount of executed statements, the amount of records
+    /// taken from the results and the may occurring error.
+    fn statement(&self, name: &'static str, query: impl IntoQuery) -> InstrumentedQuery<'_>;
+}
+
+impl Statement for DatabaseConnection {
+    fn statement(&self, name: &'static str, query: impl IntoQuery) -> InstrumentedQuery<'_> {
+        InstrumentedQuery {
+            name,
+            parameters: Vec::new(),
+            query: self.query(query),
+        }
+    }
+}
+
+pub struct InstrumentedQuery<'r> {
+    name: &'static str,
+    parameters: Vec<String>,
+    query: Query<'r, Client>,
+}
+
+impl<'r> InstrumentedQuery<'r> {
+    /// Chain another query, see [`Query::query`].
+    pub fn query(mut self, query: impl IntoQuery) -> Self {
+        self.query = self.query.query(query);
+        self
+    }
+
+    /// Bind a parameter, only its name gets recorded.
+    pub fn bind<T: Serialize>(mut self, (key, value): (&str, T)) -> Self {
+        self.parameters.push(key.to_owned());
+        self.query = self.query.bind((key, value));
+        self
+    }
+
+    async fn execute(self) -> Result<Results> {
+        let span = info_span!(
+            "surrealdb",
+            statement = self.name,
+            parameters = ?self.parameters,
+            duration_ms = Empty,
+            statements = Empty,
+            results = Empty,
+            error = Empty,
+        );
+        let name = self.name;
+
+        async move {
+            let start = Instant::now();
+            let result = match self.query.await {
+                Ok(response) => response.check(),
+                Err(error) => Err(error),
+            };
+            let duration = start.elapsed();
+
+            let span = Span::current();
+            span.record("duration_ms", duration.as_millis() as u64);
+            match result.as_ref() {
+                Ok(response) => span.record("statements", response.num_statements()),
+                Err(error) => span.record("error", display(error)),
+            };
+
+            if duration >= Duration::from_millis(CONFIGURATION.slow_query_threshold) {
+                warn!(
+                    duration_ms = duration.as_millis() as u64,
+                    "Slow surrealdb statement: {}", name
+                );
+            }
+
+            Ok(Results {
+                response: result?,
+                span,
+                taken: 0,
+            })
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// The results of an instrumented query. The records taken from them are summed up in the
+/// `results` field of its span, as the response only knows the amount of statements.
+pub struct Results {
+    response: Response,
+    span: Span,
+    taken: usize,
+}
+
+impl Results {
+    /// Take the result of a statement, see [`Response::take`].
+    pub fn take<R: Records>(&mut self, index: impl QueryResult<R>) -> Result<R> {
+        let result = self.response.take(index)?;
+        self.taken += result.records();
+        self.span.record("results", self.taken);
+
+        Ok(result)
+    }
+}
+
+/// Something taken from the results of a statement, counted in records.
+pub trait Records {
+    fn records(&self) -> usize;
+}
+
+impl<T> Records for Option<T> {
+    fn records(&self) -> usize {
+        usize::from(self.is_some())
+    }
+}
+
+impl<T> Records for Vec<T> {
+    fn records(&self) -> usize {
+        self.len()
+    }
+}
+
+impl Records for Value {
+    fn records(&self) -> usize {
+        match self {
+            Value::Array(records) => records.len(),
+            Value::None | Value::Null => 0,
+            _ => 1,
+        }
+    }
+}
+
+impl<'r> IntoFuture for InstrumentedQuery<'r> {
+    type Output = Result<Results>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send + 'r>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.execute())
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::database::{establish, Authentication, ConnectionInfo};
+use crate::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, RwLock};
+
+const SUPERVISOR_INTERVAL: Duration = Duration::from_secs(5);
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Health {
+    Connected,
+    Reconnecting {
+        attempt: u32,
+    },
+    /// The session is over for good, e.g. as its access token expired.
+    Closed,
+}
+
+impl Health {
+    pub fn is_connected(&self) -> bool {
+        matches!(self, Health::Connected)
+    }
+}
+
+/// Keeps a database session alive by regularly checking it and replacing it with a freshly
+/// authenticated one once it died. Root sessions sign in again, scope sessions authenticate with
+/// their access token again until it expires. The supervision ends once every handle is dropped.
+#[derive(Clone)]
+pub struct Supervisor {
+    connection: Arc<RwLock<DatabaseConnection>>,
+    health: watch::Receiver<Health>,
+}
+
+impl Supervisor {
+    pub fn spawn(info: ConnectionInfo, authentication: Authentication) -> Self {
+        let connection = Arc::new(RwLock::new(info.connection));
+        let (sender, health) = watch::channel(Health::Connected);
+
+        let supervised = connection.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SUPERVISOR_INTERVAL).await;
+                if sender.is_closed() {
+                    break;
+                }
+
+                let current = supervised.read().await.clone();
+                match ping(&current).await {
+                    Ok(()) => continue,
+                    Err(error) => warn!("Lost connection to surrealdb: {}", error),
+                }
+
+                let mut attempt = 0;
+                loop {
+                    // an expired access token never works again, its holder has to refresh it
+                    if let Authentication::Token(token) = &authentication {
+                        if !crate::session::valid(token) {
+                            sender.send_replace(Health::Closed);
+                            info!("Stopped supervising the expired scope session");
+                            return;
+                        }
+                    }
+
+                    attempt += 1;
+                    sender.send_replace(Health::Reconnecting { attempt });
+
+                    match establish(
+                        info.namespace.as_str(),
+                        info.database.as_str(),
+                        &authentication,
+                    )
+                    .await
+                    {
+                        Ok(connection) => {
+                            *supervised.write().await = connection;
+                            sender.send_replace(Health::Connected);
+                            info!("Reconnected to surrealdb after {} attempt(s)", attempt);
+                            break;
+                        }
+                        Err(error) => {
+                            let delay = backoff(attempt);
+                            error!(
+                                "Reconnecting to surrealdb failed, retrying in {:?}: {}",
+                                delay, error
+                            );
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        Self { connection, health }
+    }
+
+    /// The current connection, regardless of its health.
+    pub async fn connection(&self) -> DatabaseConnection {
+        self.connection.read().await.clone()
+    }
+
+    /// The current connection, if it is known to be alive.
+    pub async fn connected(&self) -> Option<DatabaseConnection> {
+        if self.health.borrow().is_connected() {
+            Some(self.connection().await)
+        } else {
+            None
+        }
+    }
+
+    pub fn health(&self) -> watch::Receiver<Health> {
+        self.health.clone()
+    }
+}
+
+async fn ping(connection: &DatabaseConnection) -> Result<()> {
+    let request = async { connection.query("RETURN true").await?.check() };
+
+    match tokio::time::timeout(PING_TIMEOUT, request).await {
+        Ok(response) => {
+            response?;
+            Ok(())
+        }
+        // a hanging session is as good as a dead one
+        Err(_) => Err(ApplicationError::InternalServerError),
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
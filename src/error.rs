@@ -31,6 +31,18 @@ pub enum ApplicationError {
     InternalServerError,
     #[error(transparent)]
     IoError(#[from] std::io::Error),
+    #[error("Detected {0} schema drift(s)")]
+    SchemaDrift(usize),
+    #[error("Unknown permission {0:?}")]
+    UnknownPermission(String),
+    #[error("{0} was changed by someone else in the meantime")]
+    VersionConflict(String),
+    #[error("Too many requests, try again later")]
+    TooManyRequests,
+    #[error("A second factor is required")]
+    SecondFactorRequired,
+    #[error("Unable to encrypt or decrypt data")]
+    CryptoError,
     #[error(transparent)]
     SMTPError(#[from] lettre::transport::smtp::Error),
 }
@@ -0,0 +1,261 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::hook::ActionType;
+use crate::prelude::*;
+use crate::CONFIGURATION;
+use std::path::PathBuf;
+use surrealdb::sql::Thing;
+
+/// How long finished exports can be downloaded before they get removed.
+const EXPORT_RETENTION: &str = "7d";
+
+#[derive(Debug, Clone, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct Export {
+    id: Thing,
+    account: Thing,
+    ready_at: Option<String>,
+    created_at: String,
+}
+
+/// The file the given export gets written to.
+pub fn path(export: &Thing) -> PathBuf {
+    PathBuf::from(CONFIGURATION.export_directory.as_str()).join(format!("{}.json", export.id))
+}
+
+/// Request a copy of all data of the signed in account. The export is created asynchronously by
+/// the hook, which announces it with a mail.
+#[instrument(skip_all)]
+pub async fn request(connection: &DatabaseConnection) -> Result<Thing> {
+    let pending: Option<i64> = connection
+        .statement(
+            "counting pending exports",
+            "SELECT count() FROM export WHERE account = $auth.id AND ready_at IS NONE GROUP ALL",
+        )
+        .await?
+        .take((0, "count"))?;
+    if pending.unwrap_or_default() > 0 {
+        return Err(ApplicationError::BadRequest(
+            "An export of this account is already being prepared".to_owned(),
+        ));
+    }
+
+    let export: Option<Thing> = connection
+        .statement("requesting export", "CREATE ONLY export RETURN VALUE id")
+        .await?
+        .take(0)?;
+
+    export.ok_or(ApplicationError::InternalServerError)
+}
+
+/// The exports of the signed in account, the most recent one first.
+pub async fn exports(connection: &DatabaseConnection) -> Result<Vec<Export>> {
+    Ok(connection
+        .statement(
+            "listing exports",
+            "SELECT * FROM export ORDER BY created_at DESC",
+        )
+        .await?
+        .take(0)?)
+}
+
+/// The archive of a finished export, only available to the exported account.
+#[instrument(skip(connection))]
+pub async fn download(connection: &DatabaseConnection, export: &Thing) -> Result<Vec<u8>> {
+    let ready: Option<Export> = connection
+        .statement(
+            "fetching export",
+            "SELECT * FROM $export WHERE ready_at IS NOT NONE",
+        )
+        .bind(("export", export))
+        .await?
+        .take(0)?;
+    if ready.is_none() {
+        return Err(ApplicationError::BadRequest(format!(
+            "The export {} is not available",
+            export
+        )));
+    }
+
+    Ok(tokio::fs::read(path(export)).await?)
+}
+
+/// Collect everything stored about the given account.
+async fn collect(connection: &DatabaseConnection, account: &Thing) -> Result<serde_json::Value> {
+    let mut response = connection
+        .statement("collecting account data", "SELECT * FROM $account")
+        .query("SELECT * FROM task_request WHERE customer = $account")
+        .query("SELECT * FROM task WHERE customer = $account")
+        .query(
+            "SELECT * FROM message
+                WHERE (author = $account OR reference.customer = $account) AND internal = false",
+        )
+        .query("SELECT * FROM notification WHERE `for` = $account")
+        // links of pending mails may hold a token, which must not leave the server
+        .query(
+            "SELECT id, recipient, type, state, locale, updated_at, created_at FROM mail
+                WHERE recipient = $account.mail",
+        )
+        .bind(("account", account))
+        .await?;
+
+    let mut data = serde_json::Map::new();
+    let mut profile: Option<serde_json::Value> = response.take(0)?;
+    // neither the hashes nor the server side secrets are of any use to anyone
+    if let Some(serde_json::Value::Object(profile)) = profile.as_mut() {
+        for field in ["password", "nonce", "secret", "recovery_codes"] {
+            profile.remove(field);
+        }
+    }
+    data.insert("account".to_owned(), profile.unwrap_or_default());
+    for (index, key) in [
+        "task_requests",
+        "tasks",
+        "messages",
+        "notifications",
+        "mails",
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let records: Vec<serde_json::Value> = response.take(index + 1)?;
+        data.insert(key.to_owned(), records.into());
+    }
+
+    Ok(data.into())
+}
+
+/// Write the archives of all pending exports and announce them by mail, called by the hook.
+#[instrument(skip_all)]
+pub async fn export_hook(connection: &DatabaseConnection) -> Result<()> {
+    let exports: Vec<Export> = connection
+        .statement(
+            "collecting pending exports",
+            "SELECT * FROM export WHERE ready_at IS NONE",
+        )
+        .await?
+        .take(0)?;
+    if exports.is_empty() {
+        return Ok(());
+    }
+
+    tokio::fs::create_dir_all(CONFIGURATION.export_directory.as_str()).await?;
+    // one failing export must not hold back the others
+    for export in exports {
+        if let Err(error) = write(connection, &export).await {
+            error!("Error occurred while exporting {}: {}", export.id, error);
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the file of a pending export and announce it by mail.
+async fn write(connection: &DatabaseConnection, export: &Export) -> Result<()> {
+    let data = collect(connection, &export.account).await?;
+    let archive = serde_json::to_vec_pretty(&data).map_err(|error| {
+        error!("Unable to serialize export {}: {}", export.id, error);
+        ApplicationError::InternalServerError
+    })?;
+    tokio::fs::write(path(&export.id), archive).await?;
+
+    connection
+        .statement(
+            "finalizing export",
+            "UPDATE $export SET ready_at = time::now()",
+        )
+        .query(
+            "CREATE mail CONTENT {
+                recipient: $export.account.mail,
+                type: $type,
+                locale: $export.account.locale
+            }",
+        )
+        .bind(("export", &export.id))
+        .bind(("type", ActionType::AccountExportReady))
+        .await?;
+    info!("Exported the data of {}", export.account);
+
+    Ok(())
+}
+
+/// Remove exports which were available for longer than the retention.
+#[instrument(skip_all)]
+pub async fn expire(connection: &DatabaseConnection) -> Result<()> {
+    let expired: Vec<Thing> = connection
+        .statement(
+            "expiring exports",
+            "DELETE export WHERE ready_at < time::now() - type::duration($retention) RETURN BEFORE",
+        )
+        .bind(("retention", EXPORT_RETENTION))
+        .await?
+        .take::<Vec<Export>>(0)?
+        .into_iter()
+        .map(|export| export.id)
+        .collect();
+
+    for export in expired {
+        if let Err(error) = tokio::fs::remove_file(path(&export)).await {
+            warn!("Unable to remove the archive of {}: {}", export, error);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixture::{customer, fresh, mail, session, task_request};
+
+    #[tokio::test]
+    async fn test_export() -> Result<()> {
+        let info = fresh().await?;
+        let alice = customer(&info, "alice").await?;
+        customer(&info, "bob").await?;
+        task_request(&info, &alice, "Online shop", "Selling ceramics online.").await?;
+        task_request(&info, &alice, "Website", "A new company website.").await?;
+        let customer = session(&info, "alice").await?;
+        let other = session(&info, "bob").await?;
+
+        crate::account::request_password_reset(&info, mail("alice").as_str()).await?;
+        let export = request(&customer).await?;
+        assert!(request(&customer).await.is_err());
+        assert!(download(&customer, &export).await.is_err());
+
+        export_hook(&info.connection).await?;
+        let archive: serde_json::Value =
+            serde_json::from_slice(download(&customer, &export).await?.as_slice()).unwrap();
+        assert!(archive["account"].get("password").is_none());
+        assert_eq!(2, archive["task_requests"].as_array().unwrap().len());
+        // the pending reset mail is exported without its token
+        let mails = archive["mails"].as_array().unwrap();
+        assert!(!mails.is_empty() && mails.iter().all(|mail| mail.get("link").is_none()));
+        assert!(download(&other, &export).await.is_err());
+
+        let mails: Vec<String> = info
+            .connection
+            .query("SELECT VALUE recipient FROM mail WHERE type = \"account_export_ready\"")
+            .await?
+            .take(0)?;
+        assert_eq!(vec![mail("alice")], mails);
+
+        tokio::fs::remove_file(path(&export)).await?;
+        Ok(())
+    }
+}
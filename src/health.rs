@@ -0,0 +1,58 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::database::supervisor::Health;
+use crate::prelude::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+/// Answer every http request on the given address with the current health of the database
+/// connection, so orchestrators can probe it.
+#[instrument(skip(health))]
+pub async fn serve(address: &str, health: watch::Receiver<Health>) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Serving health endpoint on {}", address);
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let health = *health.borrow();
+
+        tokio::spawn(async move {
+            // the request itself is irrelevant, every path reports the health
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer).await;
+
+            let body = json!({ "database": health }).to_string();
+            let status = if health.is_connected() {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+
+            if let Err(error) = stream.write_all(response.as_bytes()).await {
+                warn!("Unable to answer health request: {}", error);
+            }
+        });
+    }
+}
@@ -52,19 +52,22 @@ pub struct Mail {
     ty: ActionType,
     state: MailState,
     locale: String,
+    link: Option<String>,
 }
 
 #[instrument(skip_all)]
 pub async fn mail_hook(connection: &DatabaseConnection) -> Result<()> {
     // collect all mail with the status "pending" and update them to "processing"
-    let mails: Vec<Mail> = sql_span!(connection
-        .query("SELECT * FROM mail WHERE state = $pending")
+    let mails: Vec<Mail> = connection
+        .statement(
+            "collecting pending mails",
+            "SELECT * FROM mail WHERE state = $pending",
+        )
         .query("UPDATE mail SET state = $processing WHERE state = $pending")
         .bind(("pending", MailState::Pending))
         .bind(("processing", MailState::Processing))
         .await?
-        .check()?
-        .take(0)?);
+        .take(0)?;
 
     // send the mails
     for mail in mails {
@@ -93,6 +96,11 @@ pub async fn mail_hook(connection: &DatabaseConnection) -> Result<()> {
 }
 #[instrument(skip_all)]
 async fn send_mail(mail: Mail, connection: &DatabaseConnection) -> Result<()> {
+    let link = format!(
+        "{}{}",
+        CONFIGURATION.public_url.trim_end_matches('/'),
+        mail.link.as_deref().unwrap_or_default()
+    );
     let message = Message::builder()
         .from(CONFIGURATION.smtp_username.as_str().parse().unwrap())
         .to(mail.recipient.parse().unwrap())
@@ -104,18 +112,21 @@ async fn send_mail(mail: Mail, connection: &DatabaseConnection) -> Result<()> {
         .body(t!(
             format!("mail.{}.body", mail.ty.as_ref()).as_str(),
             locale = &mail.locale,
-            name = &mail.recipient
+            name = &mail.recipient,
+            link = &link
         ))
         .unwrap();
 
     // send the mail
     TRANSPORT.send(message).await?;
-    // set the status to delivered
-    let _: Option<Mail> = connection
-        .update(mail.id)
-        .merge(&json!({
-            "state": MailState::Delivered
-        }))
+    // set the status to delivered and drop the link, which may hold a token of the account
+    connection
+        .statement(
+            "finalizing mail",
+            "UPDATE $mail SET state = $delivered, link = NONE",
+        )
+        .bind(("mail", &mail.id))
+        .bind(("delivered", MailState::Delivered))
         .await?;
 
     Ok(())
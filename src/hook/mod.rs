@@ -29,6 +29,19 @@ pub enum ActionType {
     CreatedTaskRequest,
     UpdatedTaskState,
     UpdatedTaskRequestState,
+    ArchivedTask,
+    ArchivedTaskRequest,
+    DeletedTask,
+    DeletedTaskRequest,
+    AccountExportReady,
+    AccountDeletionScheduled,
+    PasswordReset,
+    AccountVerification,
+    MailChangeConfirmation,
+    MailChangeNotice,
+    PermissionGranted,
+    PermissionRevoked,
+    AccountLocked,
 }
 
 #[derive(Deserialize, Debug)]
@@ -38,19 +51,18 @@ struct Hook {
 
 #[instrument(skip_all)]
 pub async fn hook(connection: &DatabaseConnection) -> Result<()> {
-    let span = info_span!("Hook");
-    let _ = span.enter();
-
     // fetch the may pending hook
-    let hook: Option<Hook> = sql_span!(
-        connection
-            .query("SELECT * FROM hook WHERE pending")
-            .await?
-            .take(0)?,
-        "fetching hooks"
-    );
+    let hook: Option<Hook> = connection
+        .statement("fetching hooks", "SELECT * FROM hook WHERE pending")
+        .await?
+        .take(0)?;
 
     if let Some(hook) = hook {
+        // exports queue mails, therefore they are handled first
+        if let Err(error) = crate::export::export_hook(connection).await {
+            error!("Error occurred during export hook: {}", error);
+        }
+
         tokio::select! {
             result = mail::mail_hook(connection) => {
                 match result {
@@ -61,15 +73,10 @@ pub async fn hook(connection: &DatabaseConnection) -> Result<()> {
         };
 
         // update the hook
-        let _: Option<Hook> = sql_span!(
-            connection
-                .update(hook.id.clone())
-                .merge(&json!({
-                    "pending": false
-                }))
-                .await?,
-            "finalizing hook"
-        );
+        connection
+            .statement("finalizing hook", "UPDATE $hook SET pending = false")
+            .bind(("hook", &hook.id))
+            .await?;
     }
 
     Ok(())
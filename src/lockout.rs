@@ -0,0 +1,202 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::database::ConnectionInfo;
+use crate::hook::ActionType;
+use crate::prelude::*;
+use crate::CONFIGURATION;
+use std::time::Duration;
+use surrealdb::sql::Thing;
+
+/// The delay stops doubling after this many failures, so attackers are unable to tie up
+/// connections for long.
+const MAX_DELAY_EXPONENT: i64 = 5;
+
+/// How long to wait before checking a signin after the given amount of failures.
+fn delay(failures: i64) -> Duration {
+    let exponent = (failures - 1).clamp(0, MAX_DELAY_EXPONENT) as u32;
+
+    Duration::from_millis(
+        CONFIGURATION
+            .signin_delay
+            .saturating_mul(2u64.pow(exponent)),
+    )
+}
+
+/// Refuse locked accounts and clients with too many failed signins, and delay signins following
+/// failed ones progressively.
+#[instrument(skip(info))]
+pub async fn check(info: &ConnectionInfo, mail: &str, client: &str) -> Result<()> {
+    let mut response = info
+        .connection
+        .statement(
+            "counting failed signins",
+            "SELECT count() FROM signin_attempt
+                WHERE mail = $mail AND created_at > time::now() - type::duration($window)
+                GROUP ALL",
+        )
+        .query(
+            "SELECT count() FROM signin_attempt
+                WHERE client = $client AND created_at > time::now() - type::duration($window)
+                GROUP ALL",
+        )
+        .query("SELECT VALUE id FROM account WHERE mail = $mail AND locked_until > time::now()")
+        .bind(("mail", mail))
+        .bind(("client", client))
+        .bind(("window", CONFIGURATION.signin_window.as_str()))
+        .await?;
+    let by_mail = response
+        .take::<Option<i64>>((0, "count"))?
+        .unwrap_or_default();
+    let by_client = response
+        .take::<Option<i64>>((1, "count"))?
+        .unwrap_or_default();
+    let locked: Vec<Thing> = response.take(2)?;
+
+    if !locked.is_empty() || by_client >= CONFIGURATION.signin_client_attempts {
+        return Err(ApplicationError::TooManyRequests);
+    }
+    let failures = by_mail.max(by_client);
+    if failures > 0 {
+        tokio::time::sleep(delay(failures)).await;
+    }
+
+    Ok(())
+}
+
+/// Record a failed signin, locking the account of the mail once it failed too often within the
+/// window. The owner gets told by mail.
+///
+/// Counting and locking are not atomic, so parallel guesses may exceed the limit by the number of
+/// requests in flight before the lock applies. The per-client limit and the delay of
+/// [check] keep that number small.
+#[instrument(skip(info))]
+pub async fn failed(info: &ConnectionInfo, mail: &str, client: &str) -> Result<()> {
+    info.connection
+        .statement(
+            "recording failed signin",
+            "CREATE signin_attempt CONTENT { mail: $mail, client: $client };
+            LET $failures = (SELECT count() FROM signin_attempt
+                WHERE mail = $mail AND created_at > time::now() - type::duration($window)
+                GROUP ALL)[0].count OR 0;
+            LET $account = (SELECT id, mail, locale FROM account
+                WHERE mail = $mail AND (locked_until IS NONE OR locked_until < time::now()))[0];
+            IF $failures >= $attempts AND $account.id IS NOT NONE THEN {
+                UPDATE account SET locked_until = time::now() + type::duration($lockout) WHERE mail = $mail;
+                CREATE mail CONTENT {
+                    recipient: $account.mail,
+                    type: $type,
+                    locale: $account.locale,
+                    link: \"/password/reset\"
+                };
+                CREATE ONLY hook;
+            } END;",
+        )
+        .bind(("mail", mail))
+        .bind(("client", client))
+        .bind(("window", CONFIGURATION.signin_window.as_str()))
+        .bind(("attempts", CONFIGURATION.signin_attempts))
+        .bind(("lockout", CONFIGURATION.signin_lockout.as_str()))
+        .bind(("type", ActionType::AccountLocked))
+        .await?;
+
+    Ok(())
+}
+
+/// Forget the failed signins of a mail after it signed in successfully.
+#[instrument(skip(info))]
+pub async fn succeeded(info: &ConnectionInfo, mail: &str) -> Result<()> {
+    info.connection
+        .statement(
+            "resetting failed signins",
+            "DELETE signin_attempt WHERE mail = $mail",
+        )
+        .bind(("mail", mail))
+        .await?;
+
+    Ok(())
+}
+
+/// Remove the failed signins outside of the window, called by the maintenance.
+#[instrument(skip_all)]
+pub async fn cleanup(connection: &DatabaseConnection) -> Result<()> {
+    connection
+        .statement(
+            "removing signin attempts",
+            "DELETE signin_attempt WHERE created_at < time::now() - type::duration($window)",
+        )
+        .bind(("window", CONFIGURATION.signin_window.as_str()))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixture::{customer, fresh, mail, owner, session, CLIENT, PASSWORD};
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(Duration::from_millis(CONFIGURATION.signin_delay), delay(1));
+        assert_eq!(delay(2) * 2, delay(3));
+        assert_eq!(
+            delay(MAX_DELAY_EXPONENT + 1),
+            delay(MAX_DELAY_EXPONENT + 10)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lockout() -> Result<()> {
+        let info = fresh().await?;
+        let bob = customer(&info, "bob").await?;
+        customer(&info, "alice").await?;
+        owner(&info, "staff").await?;
+        let mail = &mail("bob");
+
+        for _ in 0..CONFIGURATION.signin_attempts {
+            assert!(matches!(
+                crate::session::login(&info, CLIENT, mail, "wrong", None).await,
+                Err(ApplicationError::Unauthorized)
+            ));
+        }
+        // the correct password does not help anymore
+        assert!(matches!(
+            crate::session::login(&info, CLIENT, mail, PASSWORD, None).await,
+            Err(ApplicationError::TooManyRequests)
+        ));
+        assert!(session(&info, "bob").await.is_err());
+        let mails: Vec<String> = info
+            .connection
+            .query("SELECT VALUE recipient FROM mail WHERE type = \"account_locked\"")
+            .await?
+            .take(0)?;
+        assert_eq!(vec![mail.to_owned()], mails);
+
+        let staff = session(&info, "staff").await?;
+        assert!(
+            crate::admin::unlock(&info, &session(&info, "alice").await?, &bob)
+                .await
+                .is_err()
+        );
+        crate::admin::unlock(&info, &staff, &bob).await?;
+        crate::session::login(&info, CLIENT, mail, PASSWORD, None).await?;
+        session(&info, "bob").await?;
+
+        Ok(())
+    }
+}
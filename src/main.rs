@@ -32,16 +32,37 @@ extern crate strum;
 #[macro_use]
 extern crate lazy_static;
 
+use crate::database::supervisor::Supervisor;
+use crate::database::Authentication;
 use crate::error::ApplicationError;
 use std::ops::Deref;
+use std::path::PathBuf;
+use std::str::FromStr;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+mod account;
+mod admin;
+mod api_token;
+mod audit;
+mod crypto;
 mod database;
 mod error;
+mod export;
+mod health;
 mod hook;
+mod lockout;
+mod maintenance;
+mod permission;
+mod revision;
+mod search;
+mod session;
+mod task;
+mod token;
+mod totp;
 
 const HOOK_INTERVAL: u64 = 10000;
+const MAINTENANCE_INTERVAL: u64 = 3600000;
 
 i18n!("locales", fallback = "en");
 
@@ -50,9 +71,51 @@ pub struct Config {
     surrealdb_endpoint: String,
     surrealdb_username: String,
     surrealdb_password: String,
+    #[serde(default = "default_namespace")]
+    surrealdb_namespace: String,
+    #[serde(default = "default_database")]
+    surrealdb_database: String,
     smtp_host: String,
     smtp_username: String,
     smtp_password: String,
+    #[serde(default)]
+    schema_strict: bool,
+    #[serde(default = "default_health_address")]
+    health_address: String,
+    /// Statements taking longer than this amount of milliseconds get logged as slow.
+    #[serde(default = "default_slow_query_threshold")]
+    slow_query_threshold: u64,
+    /// How long deleted tasks and requests are kept before being purged, e.g. `30d`.
+    #[serde(default = "default_purge_after")]
+    purge_after: String,
+    /// The directory the account data exports are written to.
+    #[serde(default = "default_export_directory")]
+    export_directory: String,
+    /// How long a requested account deletion can be cancelled, e.g. `14d`.
+    #[serde(default = "default_deletion_grace_period")]
+    deletion_grace_period: String,
+    /// The secret the access tokens of sessions are signed with.
+    session_secret: String,
+    /// The base64 encoded 32 byte key the server side encrypted fields are encrypted with.
+    secret_key: String,
+    /// The url yaud is reachable at, used for the links in mails.
+    #[serde(default = "default_public_url")]
+    public_url: String,
+    /// Failed signins of an account within the window before it gets locked.
+    #[serde(default = "default_signin_attempts")]
+    signin_attempts: i64,
+    /// Failed signins of a client within the window before it gets refused.
+    #[serde(default = "default_signin_client_attempts")]
+    signin_client_attempts: i64,
+    /// The window failed signins are counted in, e.g. `15m`.
+    #[serde(default = "default_signin_window")]
+    signin_window: String,
+    /// How long locked accounts stay locked, e.g. `30m`.
+    #[serde(default = "default_signin_lockout")]
+    signin_lockout: String,
+    /// Milliseconds the first retry after a failed signin is delayed, doubled with every failure.
+    #[serde(default = "default_signin_delay")]
+    signin_delay: u64,
     #[cfg(test)]
     test_mail: String,
     #[cfg(test)]
@@ -63,10 +126,72 @@ pub struct Config {
     test_mail_namespace: String,
 }
 
+fn default_namespace() -> String {
+    "production".to_owned()
+}
+
+fn default_database() -> String {
+    "yaud".to_owned()
+}
+
+fn default_health_address() -> String {
+    "0.0.0.0:8081".to_owned()
+}
+
+fn default_slow_query_threshold() -> u64 {
+    500
+}
+
+fn default_purge_after() -> String {
+    "30d".to_owned()
+}
+
+fn default_export_directory() -> String {
+    "exports".to_owned()
+}
+
+fn default_deletion_grace_period() -> String {
+    "14d".to_owned()
+}
+
+fn default_public_url() -> String {
+    "http://localhost:8080".to_owned()
+}
+
+fn default_signin_attempts() -> i64 {
+    5
+}
+
+fn default_signin_client_attempts() -> i64 {
+    20
+}
+
+fn default_signin_window() -> String {
+    "15m".to_owned()
+}
+
+fn default_signin_lockout() -> String {
+    "30m".to_owned()
+}
+
+fn default_signin_delay() -> u64 {
+    250
+}
+
 lazy_static! {
     pub static ref CONFIGURATION: Config = envy::from_env::<Config>().unwrap();
 }
 
+/// The mode yaud runs in, given as the first command line argument.
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+enum Mode {
+    Serve,
+    Backup,
+    Restore,
+    Seed,
+}
+
 fn app(context: Scope) -> Element {
     yaud_dioxus::app(context)
 }
@@ -80,18 +205,87 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let mut arguments = std::env::args().skip(1);
+    let mode = match arguments.next() {
+        Some(mode) => Mode::from_str(mode.as_str())?,
+        None => Mode::Serve,
+    };
+    let mut file = || {
+        arguments.next().map(PathBuf::from).ok_or_else(|| {
+            ApplicationError::BadRequest(format!("Usage: yaud {} <file>", mode.as_ref()))
+        })
+    };
+
+    match mode {
+        Mode::Serve => {}
+        Mode::Backup => {
+            let (namespace, database) = database::target(None);
+            let client =
+                database::establish(namespace.as_str(), database.as_str(), &Authentication::Root)
+                    .await?;
+
+            database::backup::export(&client, namespace.as_str(), database.as_str(), &file()?)
+                .await?;
+            return Ok(());
+        }
+        Mode::Restore => {
+            database::backup::restore(&file()?).await?;
+            return Ok(());
+        }
+        Mode::Seed => {
+            let info = database::connect(None).await?;
+            database::seed::seed(&info.connection).await?;
+            return Ok(());
+        }
+    }
+
     let (hook_sender, hook_receiver) = kanal::unbounded_async();
     let (dioxus_sender, dioxus_receiver) = kanal::unbounded_async();
 
     let info = database::connect(None).await?;
-    let connection = info.connection;
+    let supervisor = Supervisor::spawn(info, Authentication::Root);
+
+    let health = supervisor.health();
+    tokio::spawn(async move {
+        if let Err(error) = health::serve(CONFIGURATION.health_address.as_str(), health).await {
+            error!("Health endpoint stopped: {}", error);
+        }
+    });
+
+    let maintained = supervisor.clone();
+    let maintenance_receiver = hook_receiver.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = async {
+                    if let Some(connection) = maintained.connected().await {
+                        if let Err(error) = maintenance::maintenance(&connection).await {
+                            error!("Error occurred during maintenance: {}", error);
+                        }
+                    }
+
+                    tokio::time::sleep(std::time::Duration::from_millis(MAINTENANCE_INTERVAL)).await;
+                } => {},
+                _ = maintenance_receiver.recv() => {
+                    warn!("Received shutdown signal on kanal receiver");
+                    break;
+                }
+            }
+        }
+    });
 
     // as the surrealdb rust-sdk currently does not support live queries we have to adapt here
     // and are regularly checking for new hook triggers.
     tokio::spawn(async move {
         loop {
             tokio::select! {
-                result = hook::hook(&connection) => {
+                result = async {
+                    match supervisor.connected().await {
+                        Some(connection) => hook::hook(&connection).await,
+                        // the supervisor is already reconnecting
+                        None => Ok(()),
+                    }
+                } => {
                     match result {
                         Ok(()) => {},
                         Err(error) => error!("Error occurred during hook: {}", error),
@@ -120,13 +314,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     info!("Received shutdown signal... Shutting down...");
-    // shutdown
+    // shutdown, once for the hook and once for the maintenance loop
+    hook_sender.send(true).await?;
     hook_sender.send(true).await?;
     Ok(())
 }
 
 pub mod prelude {
+    pub use crate::database::statement::Statement;
     pub use crate::database::DatabaseConnection;
     pub use crate::error::*;
-    pub use crate::sql_span;
+    pub use crate::permission::Permission;
 }
@@ -0,0 +1,44 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::prelude::*;
+use crate::CONFIGURATION;
+
+/// Run the periodic cleanup jobs. Failing jobs do not prevent the others from running.
+#[instrument(skip_all)]
+pub async fn maintenance(connection: &DatabaseConnection) -> Result<()> {
+    if let Err(error) = crate::task::purge(connection, CONFIGURATION.purge_after.as_str()).await {
+        error!("Error occurred while purging deleted tasks: {}", error);
+    }
+    if let Err(error) = crate::export::expire(connection).await {
+        error!("Error occurred while expiring exports: {}", error);
+    }
+    if let Err(error) = crate::account::process_deletions(connection).await {
+        error!("Error occurred while deleting accounts: {}", error);
+    }
+    if let Err(error) = crate::session::cleanup(connection).await {
+        error!("Error occurred while removing expired sessions: {}", error);
+    }
+    if let Err(error) = crate::token::cleanup(connection).await {
+        error!("Error occurred while removing expired tokens: {}", error);
+    }
+    if let Err(error) = crate::lockout::cleanup(connection).await {
+        error!("Error occurred while removing signin attempts: {}", error);
+    }
+
+    Ok(())
+}
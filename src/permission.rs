@@ -0,0 +1,216 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::prelude::*;
+use std::str::FromStr;
+use strum::IntoEnumIterator;
+
+/// The catalog of every permission known to yaud. The `$permissions` param of the schema is
+/// generated from it.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, EnumString, AsRefStr, EnumIter,
+)]
+pub enum Permission {
+    #[strum(serialize = "admin")]
+    #[serde(rename = "admin")]
+    Admin,
+    #[strum(serialize = "task.request.select")]
+    #[serde(rename = "task.request.select")]
+    TaskRequestSelect,
+    #[strum(serialize = "task.request.edit")]
+    #[serde(rename = "task.request.edit")]
+    TaskRequestEdit,
+    #[strum(serialize = "task.request.delete")]
+    #[serde(rename = "task.request.delete")]
+    TaskRequestDelete,
+    #[strum(serialize = "task.select")]
+    #[serde(rename = "task.select")]
+    TaskSelect,
+    #[strum(serialize = "task.edit")]
+    #[serde(rename = "task.edit")]
+    TaskEdit,
+    #[strum(serialize = "task.delete")]
+    #[serde(rename = "task.delete")]
+    TaskDelete,
+    #[strum(serialize = "task.state.create")]
+    #[serde(rename = "task.state.create")]
+    TaskStateCreate,
+    #[strum(serialize = "task.state.edit")]
+    #[serde(rename = "task.state.edit")]
+    TaskStateEdit,
+    #[strum(serialize = "task.state.delete")]
+    #[serde(rename = "task.state.delete")]
+    TaskStateDelete,
+}
+
+impl Permission {
+    /// The definition of the `$permissions` param containing the entire catalog.
+    pub fn define() -> String {
+        let permissions = Permission::iter()
+            .map(|permission| permission.as_ref().to_owned())
+            .collect::<Vec<String>>();
+
+        format!("DEFINE PARAM $permissions VALUE {};", json!(permissions))
+    }
+}
+
+/// Whether the account of the given scope session holds the permission.
+pub async fn has_permission(
+    connection: &DatabaseConnection,
+    permission: Permission,
+) -> Result<bool> {
+    let result: Option<bool> = connection
+        .statement(
+            "checking permission",
+            "RETURN fn::has_permission($auth.id, type::thing(\"permission\", $permission))",
+        )
+        .bind(("permission", permission))
+        .await?
+        .take(0)?;
+
+    Ok(result.unwrap_or_default())
+}
+
+/// Whether the session was started by a login rather than a personal API token, see
+/// `fn::interactive`.
+pub async fn interactive(connection: &DatabaseConnection) -> Result<bool> {
+    let result: Option<bool> = connection
+        .statement("checking session origin", "RETURN fn::interactive()")
+        .await?
+        .take(0)?;
+
+    Ok(result.unwrap_or_default())
+}
+
+/// Fail unless the session holds the given permission.
+pub async fn authorize(connection: &DatabaseConnection, permission: Permission) -> Result<()> {
+    if !has_permission(connection, permission).await? {
+        return Err(ApplicationError::Forbidden(format!(
+            "Missing permission {}",
+            permission.as_ref()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Ensure every `type::thing("permission", ...)` literal of the given schema names a permission
+/// of the catalog.
+pub fn validate(source: &str) -> Result<()> {
+    const PATTERN: &str = "type::thing(\"permission\",";
+
+    for (index, _) in source.match_indices(PATTERN) {
+        let argument = source[index + PATTERN.len()..].trim_start();
+        // identifiers given through params are resolved at runtime
+        let Some(argument) = argument.strip_prefix('"') else {
+            continue;
+        };
+        let name = argument.split('"').next().unwrap_or_default();
+
+        if Permission::from_str(name).is_err() {
+            return Err(ApplicationError::UnknownPermission(name.to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{fixture, UP};
+
+    #[test]
+    fn test_validate_schema() {
+        assert!(validate(UP).is_ok());
+        assert!(
+            validate("fn::has_permission($auth.id, type::thing(\"permission\", $permission))")
+                .is_ok()
+        );
+
+        match validate(
+            "fn::has_permission($auth.id, type::thing(\"permission\", \"task.archive\"))",
+        ) {
+            Err(ApplicationError::UnknownPermission(name)) => assert_eq!("task.archive", name),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roles() -> Result<()> {
+        let info = fixture::fresh().await?;
+        fixture::customer(&info, "bob").await?;
+        fixture::owner(&info, "staff").await?;
+        let bob = fixture::session(&info, "bob").await?;
+        let staff = fixture::session(&info, "staff").await?;
+        assert!(has_permission(&staff, Permission::Admin).await?);
+        assert!(!has_permission(&bob, Permission::TaskSelect).await?);
+
+        staff
+            .query("RELATE account:bob->member_of->role:accountant")
+            .await?
+            .check()?;
+        assert!(has_permission(&bob, Permission::TaskSelect).await?);
+        assert!(!has_permission(&bob, Permission::TaskEdit).await?);
+        // the member is told about it
+        let notified: Vec<String> = bob
+            .query("SELECT VALUE type FROM notification WHERE `for` = account:bob")
+            .await?
+            .take(0)?;
+        assert_eq!(vec!["permission_granted"], notified);
+
+        // changes of a role apply to every member right away
+        info.connection
+            .query("RELATE role:accountant->grants->(type::thing(\"permission\", \"task.edit\"))")
+            .await?
+            .check()?;
+        assert!(has_permission(&bob, Permission::TaskEdit).await?);
+        info.connection
+            .query("DELETE grants WHERE in = role:accountant AND out = type::thing(\"permission\", \"task.edit\")")
+            .await?
+            .check()?;
+        assert!(!has_permission(&bob, Permission::TaskEdit).await?);
+
+        // only admins manage roles
+        bob.query("RELATE account:bob->member_of->role:owner")
+            .await?
+            .check()
+            .ok();
+        assert!(!has_permission(&bob, Permission::Admin).await?);
+
+        // the owner gets back every permission of the catalog on the next boot
+        info.connection
+            .query("DELETE grants WHERE in = role:owner AND out = type::thing(\"permission\", \"task.edit\")")
+            .await?
+            .check()?;
+        assert!(!has_permission(&staff, Permission::TaskEdit).await?);
+        crate::database::initiate(&info.connection).await?;
+        assert!(has_permission(&staff, Permission::TaskEdit).await?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_define_permissions() {
+        let definition = Permission::define();
+
+        assert!(definition.starts_with("DEFINE PARAM $permissions VALUE ["));
+        assert!(definition.contains("\"task.state.create\""));
+        assert!(definition.contains("\"task.state.edit\""));
+        assert!(definition.contains("\"task.state.delete\""));
+    }
+}
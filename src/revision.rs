@@ -0,0 +1,191 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::prelude::*;
+use surrealdb::sql::Thing;
+
+/// A previous version of an edited text, e.g. the description of a task or the content of a
+/// message. Revisions are created by the update events of the schema.
+#[derive(Debug, Clone, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct Revision {
+    id: Thing,
+    record: Thing,
+    field: String,
+    content: String,
+    editor: Option<Thing>,
+    created_at: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", content = "line", rename_all = "snake_case")]
+pub enum Change {
+    Kept(String),
+    Added(String),
+    Removed(String),
+}
+
+/// The previous versions of the given record, the most recent one first.
+#[instrument(skip(connection))]
+pub async fn revisions(connection: &DatabaseConnection, record: &Thing) -> Result<Vec<Revision>> {
+    Ok(connection
+        .statement(
+            "listing revisions",
+            "SELECT * FROM revision WHERE record = $record ORDER BY created_at DESC",
+        )
+        .bind(("record", record))
+        .await?
+        .take(0)?)
+}
+
+/// The changes made by the edit which replaced the given revision, which is either the next
+/// revision or the current version of the record.
+#[instrument(skip(connection))]
+pub async fn compare(connection: &DatabaseConnection, revision: &Thing) -> Result<Vec<Change>> {
+    let mut response = connection
+        .statement("fetching revision", "SELECT * FROM $revision")
+        .query(
+            "SELECT VALUE content FROM revision
+                WHERE record = $revision.record AND field = $revision.field AND created_at > $revision.created_at
+                ORDER BY created_at ASC LIMIT 1",
+        )
+        .query("SELECT * FROM $revision.record")
+        .bind(("revision", revision))
+        .await?;
+
+    let Some(previous) = response.take::<Option<Revision>>(0)? else {
+        return Err(ApplicationError::BadRequest(format!(
+            "The revision {} does not exist",
+            revision
+        )));
+    };
+    let next = match response.take::<Option<String>>(1)? {
+        Some(next) => next,
+        None => response
+            .take::<Option<serde_json::Value>>(2)?
+            .and_then(|record| record.get(previous.field()).cloned())
+            .and_then(|content| content.as_str().map(str::to_owned))
+            .unwrap_or_default(),
+    };
+
+    Ok(diff(previous.content(), next.as_str()))
+}
+
+/// A line based diff of two texts using their longest common subsequence.
+pub fn diff(old: &str, new: &str) -> Vec<Change> {
+    let old = old.lines().collect::<Vec<&str>>();
+    let new = new.lines().collect::<Vec<&str>>();
+
+    // the lengths of the longest common subsequences of all suffixes
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            changes.push(Change::Kept(old[i].to_owned()));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            changes.push(Change::Removed(old[i].to_owned()));
+            i += 1;
+        } else {
+            changes.push(Change::Added(new[j].to_owned()));
+            j += 1;
+        }
+    }
+    changes.extend(
+        old[i..]
+            .iter()
+            .map(|line| Change::Removed(line.to_string())),
+    );
+    changes.extend(new[j..].iter().map(|line| Change::Added(line.to_string())));
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixture::{customer, fresh, owner, session, task_request};
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(
+            vec![
+                Change::Kept("a".to_owned()),
+                Change::Removed("b".to_owned()),
+                Change::Added("c".to_owned()),
+                Change::Kept("d".to_owned()),
+                Change::Added("e".to_owned()),
+            ],
+            diff("a\nb\nd", "a\nc\nd\ne")
+        );
+        assert!(diff("", "").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_message_revisions() -> Result<()> {
+        let info = fresh().await?;
+        let alice = customer(&info, "alice").await?;
+        owner(&info, "staff").await?;
+        let shop = task_request(&info, &alice, "Online shop", "Selling ceramics online.").await?;
+        let customer = session(&info, "alice").await?;
+        let staff = session(&info, "staff").await?;
+
+        let message: Option<Thing> = staff
+            .query("CREATE ONLY message SET content = \"first\", reference = $shop RETURN VALUE id")
+            .bind(("shop", &shop))
+            .await?
+            .take(0)?;
+        let message = message.unwrap();
+        staff
+            .query("UPDATE $message SET content = \"second\"")
+            .bind(("message", &message))
+            .await?;
+        // only the author is allowed to edit a message
+        customer
+            .query("UPDATE $message SET content = \"third\"")
+            .bind(("message", &message))
+            .await?;
+
+        let history = revisions(&staff, &message).await?;
+        assert_eq!(1, history.len());
+        assert_eq!("first", history[0].content());
+        assert_eq!(
+            vec![
+                Change::Removed("first".to_owned()),
+                Change::Added("second".to_owned())
+            ],
+            compare(&staff, history[0].id()).await?
+        );
+
+        // the history is only visible to the staff
+        assert!(revisions(&customer, &message).await?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::prelude::*;
+use surrealdb::sql::Thing;
+
+const HIGHLIGHT_PREFIX: &str = "<mark>";
+const HIGHLIGHT_SUFFIX: &str = "</mark>";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SearchKind {
+    Task,
+    TaskRequest,
+    Message,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Hit {
+    pub id: Thing,
+    pub kind: SearchKind,
+    pub score: f64,
+    /// The highlighted title, messages do not have one.
+    pub title: Option<String>,
+    /// The highlighted description or content of a message, missing if only the title matched.
+    pub content: Option<String>,
+}
+
+/// Search tasks, task requests and messages for the given keywords. As the statements are
+/// executed within the session of the given connection, the table permissions apply and every
+/// account only finds the records it is allowed to select.
+#[instrument(skip(connection))]
+pub async fn search(
+    connection: &DatabaseConnection,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<Hit>> {
+    if query.trim().is_empty() {
+        return Err(ApplicationError::BadRequest(
+            "The search query must not be empty".to_owned(),
+        ));
+    }
+
+    let mut responses = connection
+        .statement(
+            "searching tasks",
+            "SELECT id, \"task\" AS kind,
+                    (search::score(0) OR 0) + (search::score(1) OR 0) AS score,
+                    search::highlight($prefix, $suffix, 0) AS title,
+                    search::highlight($prefix, $suffix, 1) AS content
+                FROM task
+                WHERE (title @0@ $query OR description @1@ $query) AND deleted_at IS NONE
+                ORDER BY score DESC LIMIT $limit",
+        )
+        .query(
+            "SELECT id, \"task_request\" AS kind,
+                    (search::score(0) OR 0) + (search::score(1) OR 0) AS score,
+                    search::highlight($prefix, $suffix, 0) AS title,
+                    search::highlight($prefix, $suffix, 1) AS content
+                FROM task_request
+                WHERE (title @0@ $query OR description @1@ $query) AND deleted_at IS NONE
+                ORDER BY score DESC LIMIT $limit",
+        )
+        .query(
+            "SELECT id, \"message\" AS kind,
+                    search::score(0) AS score,
+                    search::highlight($prefix, $suffix, 0) AS content
+                FROM message WHERE content @0@ $query
+                ORDER BY score DESC LIMIT $limit",
+        )
+        .bind(("query", query))
+        .bind(("limit", limit))
+        .bind(("prefix", HIGHLIGHT_PREFIX))
+        .bind(("suffix", HIGHLIGHT_SUFFIX))
+        .await?;
+
+    let mut hits = Vec::new();
+    for index in 0..3 {
+        hits.extend(responses.take::<Vec<Hit>>(index)?);
+    }
+    // merge the results of all tables by their relevance
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.truncate(limit);
+
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixture::{customer, fresh, task, task_request};
+
+    #[tokio::test]
+    async fn test_search() -> Result<()> {
+        let info = fresh().await?;
+        let alice = customer(&info, "alice").await?;
+        let website = task(
+            &info,
+            &alice,
+            "Relaunch of the company website",
+            "Responsive website including a blog.",
+        )
+        .await?;
+        task_request(
+            &info,
+            &alice,
+            "Relaunch of the company website",
+            "Our current website is not usable on phones.",
+        )
+        .await?;
+        let shop = task_request(
+            &info,
+            &alice,
+            "Online shop for handmade ceramics",
+            "Around 40 products, payment via invoice.",
+        )
+        .await?;
+        let connection = &info.connection;
+
+        let hits = search(connection, "websites", 10).await?;
+        assert!(hits
+            .iter()
+            .any(|hit| hit.kind == SearchKind::Task && hit.id == website));
+        assert!(hits.iter().any(|hit| hit.kind == SearchKind::TaskRequest));
+        let highlighted =
+            |text: &Option<String>| text.iter().any(|text| text.contains(HIGHLIGHT_PREFIX));
+        assert!(hits
+            .iter()
+            .all(|hit| highlighted(&hit.content) || highlighted(&hit.title)));
+
+        // only the title of the shop request mentions it
+        let hits = search(connection, "handmade", 10).await?;
+        let hit = hits.iter().find(|hit| hit.id == shop).unwrap();
+        assert!(highlighted(&hit.title));
+        assert!(hit.content.is_none());
+
+        assert!(search(connection, " ", 10).await.is_err());
+
+        Ok(())
+    }
+}
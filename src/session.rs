@@ -0,0 +1,509 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::account::AccountType;
+use crate::audit::{self, ActionLogType};
+use crate::database::supervisor::Supervisor;
+use crate::database::{establish, Authentication, ConnectionInfo};
+use crate::prelude::*;
+use crate::CONFIGURATION;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::distributions::{Alphanumeric, DistString};
+use std::time::{SystemTime, UNIX_EPOCH};
+use surrealdb::sql::Thing;
+
+/// How long an access token is valid, in seconds.
+const LIFETIME: u64 = 15 * 60;
+/// How long an expired session can still be refreshed, in seconds.
+const REFRESH_WINDOW: u64 = 5 * 60;
+/// The name of the token definition on the `account` scope.
+const TOKEN: &str = "session";
+
+/// The claims of an access token. Besides the session, surrealdb requires the namespace,
+/// database, scope, token definition and the account the token authenticates as.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AccessTokenClaims {
+    pub session: String,
+    iat: u64,
+    nbf: u64,
+    exp: u64,
+    #[serde(rename = "NS")]
+    ns: String,
+    #[serde(rename = "DB")]
+    db: String,
+    #[serde(rename = "SC")]
+    sc: String,
+    #[serde(rename = "TK")]
+    tk: String,
+    #[serde(rename = "ID")]
+    id: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct Session {
+    id: Thing,
+    account: Thing,
+    iat: String,
+    exp: String,
+}
+
+/// The tokens handed to the client of a session.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tokens {
+    pub session: Thing,
+    pub access_token: String,
+    pub refresh_token: String,
+    /// The seconds until the access token expires.
+    pub expires_in: u64,
+    /// Lets the frontend pick the dashboard of the account.
+    pub account_type: AccountType,
+}
+
+#[derive(Debug, Deserialize)]
+struct Issued {
+    id: Thing,
+    account: Thing,
+    account_type: AccountType,
+}
+
+/// A new refresh token, only its hash gets stored.
+fn generate_refresh_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 64)
+}
+
+/// The definition of the token access tokens are verified with.
+pub fn define() -> String {
+    format!(
+        "DEFINE TOKEN {TOKEN} ON SCOPE account TYPE HS512 VALUE {};",
+        json!(CONFIGURATION.session_secret)
+    )
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Sign the access token of the given session.
+fn sign(info: &ConnectionInfo, issued: &Issued, refresh_token: String) -> Result<Tokens> {
+    let now = now()?;
+    let claims = AccessTokenClaims {
+        session: issued.id.id.to_raw(),
+        iat: now,
+        nbf: now,
+        exp: now + LIFETIME,
+        ns: info.namespace.clone(),
+        db: info.database.clone(),
+        sc: "account".to_owned(),
+        tk: TOKEN.to_owned(),
+        id: issued.account.to_string(),
+    };
+    let access_token = jsonwebtoken::encode(
+        &Header::new(Algorithm::HS512),
+        &claims,
+        &EncodingKey::from_secret(CONFIGURATION.session_secret.as_bytes()),
+    )
+    .map_err(|error| {
+        error!("Unable to sign access token: {}", error);
+        ApplicationError::InternalServerError
+    })?;
+
+    Ok(Tokens {
+        session: issued.id.clone(),
+        access_token,
+        refresh_token,
+        expires_in: LIFETIME,
+        account_type: issued.account_type,
+    })
+}
+
+/// Verify the signature and expiration of an access token.
+fn verify(access_token: &str) -> Result<AccessTokenClaims> {
+    let validation = Validation::new(Algorithm::HS512);
+
+    jsonwebtoken::decode::<AccessTokenClaims>(
+        access_token,
+        &DecodingKey::from_secret(CONFIGURATION.session_secret.as_bytes()),
+        &validation,
+    )
+    .map(|data| data.claims)
+    .map_err(|_| ApplicationError::Unauthorized)
+}
+
+/// Whether the signature of the given access token is valid and it did not expire yet. It may
+/// have been revoked nonetheless.
+pub fn valid(access_token: &str) -> bool {
+    verify(access_token).is_ok()
+}
+
+/// The account the given scope session is signed in as.
+pub async fn account(connection: &DatabaseConnection) -> Result<Thing> {
+    let account: Option<Thing> = connection
+        .statement("fetching session account", "RETURN $auth.id")
+        .await?
+        .take(0)?;
+
+    account.ok_or(ApplicationError::Unauthorized)
+}
+
+/// Start a new session for the given account, using the root connection of the given info.
+/// Sessions of a personal API token are limited to its permissions.
+#[instrument(skip(info))]
+pub async fn issue(
+    info: &ConnectionInfo,
+    account: &Thing,
+    api_token: Option<&Thing>,
+) -> Result<Tokens> {
+    let refresh_token = generate_refresh_token();
+    let issued: Option<Issued> = info
+        .connection
+        .statement(
+            "issuing session",
+            "CREATE ONLY type::thing(\"session\", rand::string(64)) CONTENT {
+                account: $account,
+                api_token: $api_token,
+                refresh_token: crypto::sha256($refresh_token),
+                iat: time::now(),
+                exp: time::now() + type::duration($lifetime)
+            } RETURN id, account, account.type AS account_type",
+        )
+        .bind(("account", account))
+        .bind(("api_token", api_token))
+        .bind(("refresh_token", refresh_token.as_str()))
+        .bind(("lifetime", format!("{LIFETIME}s")))
+        .await?
+        .take(0)?;
+
+    sign(
+        info,
+        &issued.ok_or(ApplicationError::InternalServerError)?,
+        refresh_token,
+    )
+}
+
+/// Exchange the refresh token of a session for new tokens. Sessions can be refreshed until the
+/// refresh window after their expiration is over, every refresh token is only usable once.
+#[instrument(skip_all)]
+pub async fn refresh(info: &ConnectionInfo, refresh_token: &str) -> Result<Tokens> {
+    let rotated = generate_refresh_token();
+    let issued: Vec<Issued> = info
+        .connection
+        .statement(
+            "refreshing session",
+            "UPDATE session SET
+                    refresh_token = crypto::sha256($new),
+                    iat = time::now(),
+                    exp = time::now() + type::duration($lifetime)
+                WHERE refresh_token = crypto::sha256($refresh_token)
+                    AND exp + type::duration($window) > time::now()
+                RETURN id, account, account.type AS account_type",
+        )
+        .bind(("refresh_token", refresh_token))
+        .bind(("new", rotated.as_str()))
+        .bind(("lifetime", format!("{LIFETIME}s")))
+        .bind(("window", format!("{REFRESH_WINDOW}s")))
+        .await?
+        .take(0)?;
+
+    sign(
+        info,
+        issued.first().ok_or(ApplicationError::Unauthorized)?,
+        rotated,
+    )
+}
+
+/// Connect as the account of the given access token, as long as its session was not revoked.
+#[instrument(skip_all)]
+pub async fn authenticate(info: &ConnectionInfo, access_token: &str) -> Result<DatabaseConnection> {
+    let claims = verify(access_token)?;
+    let active: Option<Session> = info
+        .connection
+        .statement(
+            "checking session",
+            "SELECT * FROM type::thing(\"session\", $session) WHERE exp > time::now()",
+        )
+        .bind(("session", &claims.session))
+        .await?
+        .take(0)?;
+    if active.is_none() {
+        return Err(ApplicationError::Unauthorized);
+    }
+
+    establish(
+        info.namespace.as_str(),
+        info.database.as_str(),
+        &Authentication::Token(access_token.to_owned()),
+    )
+    .await
+    .map_err(|_| ApplicationError::Unauthorized)
+}
+
+/// Like [authenticate], but keeps the connection alive, see [Supervisor].
+#[instrument(skip_all)]
+pub async fn supervise(info: &ConnectionInfo, access_token: &str) -> Result<Supervisor> {
+    let connection = authenticate(info, access_token).await?;
+
+    Ok(Supervisor::spawn(
+        ConnectionInfo {
+            connection,
+            database: info.database.clone(),
+            namespace: info.namespace.clone(),
+        },
+        Authentication::Token(access_token.to_owned()),
+    ))
+}
+
+/// The sessions of the signed in account, the most recent one first.
+pub async fn sessions(connection: &DatabaseConnection) -> Result<Vec<Session>> {
+    Ok(connection
+        .statement(
+            "listing sessions",
+            "SELECT * FROM session ORDER BY iat DESC",
+        )
+        .await?
+        .take(0)?)
+}
+
+/// End one of the sessions of the signed in account.
+#[instrument(skip(connection))]
+pub async fn revoke(connection: &DatabaseConnection, session: &Thing) -> Result<()> {
+    let revoked: Vec<Session> = connection
+        .statement("revoking session", "DELETE $session RETURN BEFORE")
+        .bind(("session", session))
+        .await?
+        .take(0)?;
+
+    if revoked.is_empty() {
+        return Err(ApplicationError::BadRequest(format!(
+            "The session {} does not exist",
+            session
+        )));
+    }
+
+    Ok(())
+}
+
+/// End every session of the signed in account, logging it out on all devices. Connections
+/// established before lose their access right away, as the permissions check the session as well.
+#[instrument(skip_all)]
+pub async fn revoke_all(connection: &DatabaseConnection) -> Result<()> {
+    connection
+        .statement(
+            "revoking all sessions",
+            "DELETE session WHERE account = $auth.id",
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Remove the sessions which can no longer be refreshed, called by the maintenance.
+#[instrument(skip_all)]
+pub async fn cleanup(connection: &DatabaseConnection) -> Result<()> {
+    connection
+        .statement(
+            "removing expired sessions",
+            "DELETE session WHERE exp + type::duration($window) < time::now()",
+        )
+        .bind(("window", format!("{REFRESH_WINDOW}s")))
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    id: Thing,
+    totp_enabled_at: Option<String>,
+}
+
+/// Sign in with the credentials of an account, start a session and record the login. Accounts
+/// with TOTP enabled additionally require a code. As the scope has no signin of its own, this is
+/// the only way to sign in. Failed attempts are counted per mail and per `client`, usually its
+/// address, see src/lockout.rs.
+#[instrument(skip(info, password, code))]
+pub async fn login(
+    info: &ConnectionInfo,
+    client: &str,
+    mail: &str,
+    password: &str,
+    code: Option<&str>,
+) -> Result<Tokens> {
+    crate::lockout::check(info, mail, client).await?;
+
+    crate::account::upgrade_password(info, mail, password).await?;
+    let key = crate::account::derive_key(info, mail, password).await?;
+    let credentials: Option<Credentials> = info
+        .connection
+        .statement(
+            "checking credentials",
+            "SELECT id, totp_enabled_at FROM account
+                WHERE mail = $mail AND crypto::argon2::compare(password, $password)",
+        )
+        .bind(("mail", mail))
+        .bind(("password", crate::crypto::encode_key(&key)))
+        .await?
+        .take(0)?;
+    let Some(credentials) = credentials else {
+        crate::lockout::failed(info, mail, client).await?;
+        return Err(ApplicationError::Unauthorized);
+    };
+
+    if credentials.totp_enabled_at.is_some() {
+        let code = code.ok_or(ApplicationError::SecondFactorRequired)?;
+        if !crate::totp::verify(info, &credentials.id, code).await? {
+            crate::lockout::failed(info, mail, client).await?;
+            return Err(ApplicationError::Unauthorized);
+        }
+    }
+    crate::lockout::succeeded(info, mail).await?;
+    audit::record(
+        &info.connection,
+        ActionLogType::Login,
+        Some(&credentials.id),
+        &credentials.id,
+    )
+    .await?;
+
+    issue(info, &credentials.id, None).await
+}
+
+/// End the session of the given access token and record the logout.
+#[instrument(skip_all)]
+pub async fn logout(info: &ConnectionInfo, access_token: &str) -> Result<()> {
+    let claims = verify(access_token)?;
+    let revoked: Vec<Session> = info
+        .connection
+        .statement(
+            "ending session",
+            "DELETE type::thing(\"session\", $session) RETURN BEFORE",
+        )
+        .bind(("session", &claims.session))
+        .await?
+        .take(0)?;
+
+    if let Some(session) = revoked.first() {
+        audit::record(
+            &info.connection,
+            ActionLogType::Logout,
+            Some(&session.account),
+            &session.account,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Filter;
+    use crate::database::fixture::{customer, fresh, mail, owner, CLIENT, PASSWORD};
+
+    #[tokio::test]
+    async fn test_login() -> Result<()> {
+        let info = fresh().await?;
+        let alice = customer(&info, "alice").await?;
+        let staff = owner(&info, "staff").await?;
+
+        assert!(login(&info, CLIENT, mail("alice").as_str(), "wrong", None)
+            .await
+            .is_err());
+        let tokens = login(&info, CLIENT, mail("alice").as_str(), PASSWORD, None).await?;
+        logout(&info, tokens.access_token.as_str()).await?;
+        assert!(authenticate(&info, tokens.access_token.as_str())
+            .await
+            .is_err());
+
+        let tokens = login(&info, CLIENT, mail("staff").as_str(), PASSWORD, None).await?;
+        let admin = authenticate(&info, tokens.access_token.as_str()).await?;
+        let entries = audit::logs(
+            &admin,
+            &Filter {
+                author: Some(alice),
+                ..Default::default()
+            },
+        )
+        .await?;
+        let types = entries.iter().map(|entry| *entry.ty()).collect::<Vec<_>>();
+        assert!(types.contains(&ActionLogType::Login));
+        assert!(types.contains(&ActionLogType::Logout));
+
+        // supervised connections authenticate with the access token as well
+        let supervisor = supervise(&info, tokens.access_token.as_str()).await?;
+        assert_eq!(staff, account(&supervisor.connection().await).await?);
+        assert!(supervisor.health().borrow().is_connected());
+        assert!(!valid("invalid"));
+        assert!(supervise(&info, "invalid").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sessions() -> Result<()> {
+        let info = fresh().await?;
+        let alice = customer(&info, "alice").await?;
+        let first = login(&info, CLIENT, mail("alice").as_str(), PASSWORD, None).await?;
+        let second = login(&info, CLIENT, mail("alice").as_str(), PASSWORD, None).await?;
+
+        assert_eq!(AccountType::Customer, first.account_type);
+        let connection = authenticate(&info, first.access_token.as_str()).await?;
+        assert_eq!(alice, account(&connection).await?);
+        assert_eq!(2, sessions(&connection).await?.len());
+
+        // only the hashes of the refresh tokens are stored
+        let stored: Vec<String> = info
+            .connection
+            .query("SELECT VALUE refresh_token FROM session")
+            .await?
+            .take(0)?;
+        assert!(!stored.contains(&first.refresh_token));
+
+        // every refresh token is only usable once
+        let refreshed = refresh(&info, first.refresh_token.as_str()).await?;
+        assert_eq!(first.session, refreshed.session);
+        assert!(refresh(&info, first.refresh_token.as_str()).await.is_err());
+
+        revoke(&connection, &second.session).await?;
+        assert!(authenticate(&info, second.access_token.as_str())
+            .await
+            .is_err());
+        let established = authenticate(&info, refreshed.access_token.as_str()).await?;
+        let own = "SELECT * FROM account";
+        assert_eq!(
+            1,
+            established
+                .query(own)
+                .await?
+                .take::<Vec<serde_json::Value>>(0)?
+                .len()
+        );
+        revoke_all(&connection).await?;
+        assert!(authenticate(&info, refreshed.access_token.as_str())
+            .await
+            .is_err());
+        // the access token is still valid, but its session is gone
+        assert!(established
+            .query(own)
+            .await?
+            .take::<Vec<serde_json::Value>>(0)?
+            .is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::permission::authorize;
+use crate::prelude::*;
+use std::str::FromStr;
+use surrealdb::sql::Thing;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TaskTable {
+    Task,
+    TaskRequest,
+}
+
+impl TaskTable {
+    /// The table of the given record, failing for records of any other table.
+    pub fn of(id: &Thing) -> Result<Self> {
+        TaskTable::from_str(id.tb.as_str()).map_err(|_| {
+            ApplicationError::BadRequest(format!("{} is neither a task nor a request", id))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct Task {
+    id: Thing,
+    title: String,
+    customer: Thing,
+    description: String,
+    due: String,
+    state: String,
+    priority: String,
+    version: u64,
+    archived_at: Option<String>,
+    deleted_at: Option<String>,
+    updated_at: String,
+    created_at: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct TaskRequest {
+    id: Thing,
+    title: String,
+    customer: Thing,
+    description: String,
+    due: Option<String>,
+    state: String,
+    version: u64,
+    archived_at: Option<String>,
+    deleted_at: Option<String>,
+    updated_at: String,
+    created_at: String,
+}
+
+/// The records excluded from listings by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Include {
+    pub archived: bool,
+    pub deleted: bool,
+}
+
+async fn list<T: serde::de::DeserializeOwned>(
+    connection: &DatabaseConnection,
+    table: TaskTable,
+    include: Include,
+) -> Result<Vec<T>> {
+    Ok(connection
+        .statement(
+            "listing tasks",
+            "SELECT * FROM type::table($table)
+                WHERE ($archived OR archived_at IS NONE) AND ($deleted OR deleted_at IS NONE)
+                ORDER BY created_at DESC",
+        )
+        .bind(("table", table))
+        .bind(("archived", include.archived))
+        .bind(("deleted", include.deleted))
+        .await?
+        .take(0)?)
+}
+
+/// The tasks visible to the session of the given connection.
+pub async fn tasks(connection: &DatabaseConnection, include: Include) -> Result<Vec<Task>> {
+    list(connection, TaskTable::Task, include).await
+}
+
+/// The task requests visible to the session of the given connection.
+pub async fn task_requests(
+    connection: &DatabaseConnection,
+    include: Include,
+) -> Result<Vec<TaskRequest>> {
+    list(connection, TaskTable::TaskRequest, include).await
+}
+
+impl TaskTable {
+    fn permission(&self, edit: bool) -> Permission {
+        match (self, edit) {
+            (TaskTable::Task, true) => Permission::TaskEdit,
+            (TaskTable::Task, false) => Permission::TaskDelete,
+            (TaskTable::TaskRequest, true) => Permission::TaskRequestEdit,
+            (TaskTable::TaskRequest, false) => Permission::TaskRequestDelete,
+        }
+    }
+}
+
+/// Apply the given lifecycle changes. Field permissions silently drop denied changes, therefore
+/// the callers have to authorize them beforehand.
+async fn change(connection: &DatabaseConnection, id: &Thing, changes: &'static str) -> Result<()> {
+    connection
+        .statement(
+            "changing task lifecycle",
+            format!("UPDATE $id SET {changes}"),
+        )
+        .bind(("id", id))
+        .await?;
+
+    Ok(())
+}
+
+/// Apply the given changes to a task or request, unless someone else changed it since the given
+/// version was read. Returns the new version, which stays the same if nothing changed.
+#[instrument(skip(connection, changes))]
+pub async fn update(
+    connection: &DatabaseConnection,
+    id: &Thing,
+    version: u64,
+    changes: &serde_json::Value,
+) -> Result<u64> {
+    // field permissions would silently drop the changes, see `change`
+    authorize(connection, TaskTable::of(id)?.permission(true)).await?;
+
+    let mut response = connection
+        .statement(
+            "updating task",
+            "UPDATE $id MERGE $changes WHERE version = $version RETURN VALUE version",
+        )
+        .query("SELECT VALUE version FROM $id")
+        .bind(("id", id))
+        .bind(("version", version))
+        .bind(("changes", changes))
+        .await?;
+
+    if let Some(updated) = response.take::<Option<u64>>(0)? {
+        return Ok(updated);
+    }
+    match response.take::<Option<u64>>(1)? {
+        Some(current) if current != version => {
+            Err(ApplicationError::VersionConflict(id.to_string()))
+        }
+        Some(_) => Err(ApplicationError::Forbidden(format!(
+            "Not allowed to update {}",
+            id
+        ))),
+        None => Err(ApplicationError::BadRequest(format!(
+            "{} does not exist",
+            id
+        ))),
+    }
+}
+
+/// Hide the task or request from the default listings, staff only.
+#[instrument(skip(connection))]
+pub async fn archive(connection: &DatabaseConnection, id: &Thing) -> Result<()> {
+    authorize(connection, TaskTable::of(id)?.permission(true)).await?;
+    change(connection, id, "archived_at = time::now()").await
+}
+
+/// Soft delete the task or request, which keeps it around until it gets purged. Customers are
+/// allowed to delete their own requests.
+#[instrument(skip(connection))]
+pub async fn delete(connection: &DatabaseConnection, id: &Thing) -> Result<()> {
+    let table = TaskTable::of(id)?;
+
+    if table == TaskTable::TaskRequest {
+        let owned: Option<bool> = connection
+            .statement("checking task ownership", "RETURN $id.customer = $auth.id")
+            .bind(("id", id))
+            .await?
+            .take(0)?;
+
+        if owned.unwrap_or_default() {
+            return change(connection, id, "deleted_at = time::now()").await;
+        }
+    }
+
+    authorize(connection, table.permission(false)).await?;
+    change(connection, id, "deleted_at = time::now()").await
+}
+
+/// Bring back an archived or deleted task or request, staff only.
+#[instrument(skip(connection))]
+pub async fn restore(connection: &DatabaseConnection, id: &Thing) -> Result<()> {
+    let table = TaskTable::of(id)?;
+
+    authorize(connection, table.permission(true)).await?;
+    authorize(connection, table.permission(false)).await?;
+    change(connection, id, "archived_at = NONE, deleted_at = NONE").await
+}
+
+/// Finally remove everything deleted longer ago than the retention, including its messages.
+#[instrument(skip(connection))]
+pub async fn purge(connection: &DatabaseConnection, retention: &str) -> Result<()> {
+    let purged: Vec<Thing> = connection
+        .statement(
+            "purging deleted tasks",
+            "SELECT VALUE id FROM task, task_request
+                WHERE deleted_at < time::now() - type::duration($retention)",
+        )
+        .bind(("retention", retention))
+        .await?
+        .take(0)?;
+
+    if !purged.is_empty() {
+        connection
+            .statement(
+                "purging revisions of deleted tasks",
+                "DELETE revision WHERE record INSIDE $purged OR record.reference INSIDE $purged",
+            )
+            .query("DELETE message WHERE reference INSIDE $purged")
+            .query("DELETE $purged")
+            .bind(("purged", &purged))
+            .await?;
+        info!("Purged {} deleted task(s) and request(s)", purged.len());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixture::{self, fresh, owner, session, task, task_request};
+
+    #[tokio::test]
+    async fn test_soft_delete() -> Result<()> {
+        let info = fresh().await?;
+        let alice = fixture::customer(&info, "alice").await?;
+        owner(&info, "staff").await?;
+        let customer = session(&info, "alice").await?;
+        let staff = session(&info, "staff").await?;
+        let shop = task_request(&info, &alice, "Online shop", "Selling ceramics online.").await?;
+
+        // customers are neither able to archive nor to restore
+        assert!(archive(&customer, &shop).await.is_err());
+        delete(&customer, &shop).await?;
+        assert!(restore(&customer, &shop).await.is_err());
+
+        let requests = task_requests(&customer, Include::default()).await?;
+        assert!(requests.iter().all(|request| request.id().ne(&shop)));
+        let requests = task_requests(
+            &staff,
+            Include {
+                archived: false,
+                deleted: true,
+            },
+        )
+        .await?;
+        assert!(requests.iter().any(|request| request.id().eq(&shop)));
+
+        restore(&staff, &shop).await?;
+        // the deletion time is not up to the client, otherwise it could be purged right away
+        customer
+            .query("UPDATE $shop SET deleted_at = \"2000-01-01T00:00:00Z\"")
+            .bind(("shop", &shop))
+            .await?
+            .check()?;
+        purge(&info.connection, "1d").await?;
+        restore(&staff, &shop).await?;
+        let requests = task_requests(&customer, Include::default()).await?;
+        assert!(requests.iter().any(|request| request.id().eq(&shop)));
+
+        // nothing was deleted long enough to be purged
+        purge(&info.connection, "1d").await?;
+        assert_eq!(1, task_requests(&staff, Include::default()).await?.len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version_conflict() -> Result<()> {
+        let info = fresh().await?;
+        let alice = fixture::customer(&info, "alice").await?;
+        let bob = fixture::customer(&info, "bob").await?;
+        owner(&info, "staff").await?;
+        let booking = task(&info, &bob, "Appointment booking", "Online booking.").await?;
+        let shop = task_request(&info, &alice, "Online shop", "Selling ceramics online.").await?;
+        let staff = session(&info, "staff").await?;
+
+        let version = update(&staff, &booking, 0, &json!({ "priority": "low" })).await?;
+        assert_eq!(1, version);
+        // a second editor still holding the first version
+        match update(&staff, &booking, 0, &json!({ "priority": "high" })).await {
+            Err(ApplicationError::VersionConflict(id)) => assert_eq!(booking.to_string(), id),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            2,
+            update(&staff, &booking, version, &json!({ "priority": "high" })).await?
+        );
+
+        // unchanged fields keep the version
+        assert_eq!(
+            2,
+            update(&staff, &booking, 2, &json!({ "priority": "high" })).await?
+        );
+
+        let customer = session(&info, "bob").await?;
+        assert!(matches!(
+            update(&customer, &booking, 2, &json!({ "priority": "medium" })).await,
+            Err(ApplicationError::Forbidden(_))
+        ));
+
+        // customers are allowed to update their requests, but not to edit them
+        let customer = session(&info, "alice").await?;
+        assert!(matches!(
+            update(&customer, &shop, 0, &json!({ "title": "Shop" })).await,
+            Err(ApplicationError::Forbidden(_))
+        ));
+        assert_eq!(
+            1,
+            update(&staff, &shop, 0, &json!({ "title": "Shop" })).await?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::prelude::*;
+use surrealdb::sql::Thing;
+
+/// What a mailed one-time token can be used for. Tokens are issued by `fn::issue_token` and only
+/// their hashes are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPurpose {
+    PasswordReset,
+    Verification,
+    MailChange,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Consumed {
+    pub account: Thing,
+    pub payload: Option<serde_json::Value>,
+}
+
+/// Use up a token of the given purpose, failing for unknown, used or expired tokens.
+#[instrument(skip(connection, token))]
+pub async fn consume(
+    connection: &DatabaseConnection,
+    purpose: TokenPurpose,
+    token: &str,
+) -> Result<Consumed> {
+    let consumed: Vec<Consumed> = connection
+        .statement(
+            "consuming token",
+            "UPDATE token SET used_at = time::now()
+                WHERE hash = crypto::sha256($token) AND purpose = $purpose
+                    AND used_at IS NONE AND expires_at > time::now()
+                RETURN account, payload",
+        )
+        .bind(("token", token))
+        .bind(("purpose", purpose))
+        .await?
+        .take(0)?;
+
+    consumed
+        .into_iter()
+        .next()
+        .ok_or_else(|| ApplicationError::BadRequest("The token is invalid or expired".to_owned()))
+}
+
+/// Remove used and expired tokens, called by the maintenance.
+#[instrument(skip_all)]
+pub async fn cleanup(connection: &DatabaseConnection) -> Result<()> {
+    connection
+        .statement(
+            "removing expired tokens",
+            "DELETE token WHERE used_at IS NOT NONE OR expires_at < time::now()",
+        )
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,302 @@
+/*
+ *     Copyright (C) 2023  Fritz Ochsmann
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::crypto;
+use crate::database::ConnectionInfo;
+use crate::prelude::*;
+use rand::distributions::{Alphanumeric, DistString};
+use std::time::{SystemTime, UNIX_EPOCH};
+use surrealdb::sql::Thing;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const ISSUER: &str = "yaud";
+/// The amount of recovery codes handed out when enabling TOTP.
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 12;
+
+/// What the authenticator app of the user needs to know.
+#[derive(Debug, Clone, Serialize)]
+pub struct Enrolment {
+    /// The `otpauth://` uri of the secret.
+    pub uri: String,
+    /// The uri as base64 encoded png QR code.
+    pub qr: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountSecret {
+    mail: String,
+    secret: Option<String>,
+    totp_enabled_at: Option<String>,
+}
+
+/// A new `AccountSecret`: 32 random bytes, base32 encoded and encrypted with the server key.
+fn generate_secret() -> Result<String> {
+    let bytes: [u8; 32] = rand::random();
+    let encoded = Secret::Raw(bytes.to_vec()).to_encoded().to_string();
+
+    crypto::encrypt(&crypto::server_key()?, encoded.as_bytes())
+}
+
+/// The TOTP of the given account and whether it is enabled.
+async fn load(info: &ConnectionInfo, account: &Thing) -> Result<(TOTP, bool)> {
+    let secret: Option<AccountSecret> = info
+        .connection
+        .statement(
+            "fetching account secret",
+            "SELECT mail, secret, totp_enabled_at FROM $account",
+        )
+        .bind(("account", account))
+        .await?
+        .take(0)?;
+    let secret = secret.ok_or(ApplicationError::Unauthorized)?;
+    let Some(encrypted) = secret.secret.as_ref() else {
+        return Err(ApplicationError::BadRequest(
+            "No TOTP secret was generated yet".to_owned(),
+        ));
+    };
+
+    let encoded = String::from_utf8(crypto::decrypt(&crypto::server_key()?, encrypted)?)
+        .map_err(|_| ApplicationError::CryptoError)?;
+    let bytes = Secret::Encoded(encoded)
+        .to_bytes()
+        .map_err(|_| ApplicationError::CryptoError)?;
+    let totp = TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        bytes,
+        Some(ISSUER.to_owned()),
+        secret.mail,
+    )
+    .map_err(|error| {
+        error!("Unable to create TOTP: {}", error);
+        ApplicationError::InternalServerError
+    })?;
+
+    Ok((totp, secret.totp_enabled_at.is_some()))
+}
+
+/// Accept a code of the TOTP of the given account, unless its time step or a later one was
+/// already used. Codes are valid for more than one step, so they could be replayed otherwise.
+async fn use_code(info: &ConnectionInfo, account: &Thing, totp: &TOTP, code: &str) -> Result<bool> {
+    let current = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() / totp.step;
+    let skew = totp.skew as u64;
+    let Some(step) = (current.saturating_sub(skew)..=current + skew)
+        .find(|step| totp.generate(step * totp.step) == code)
+    else {
+        return Ok(false);
+    };
+
+    let used: Vec<Thing> = info
+        .connection
+        .statement(
+            "using TOTP code",
+            "UPDATE $account SET totp_last_step = $step
+                WHERE totp_last_step IS NONE OR totp_last_step < $step RETURN VALUE id",
+        )
+        .bind(("account", account))
+        .bind(("step", step))
+        .await?
+        .take(0)?;
+
+    Ok(!used.is_empty())
+}
+
+/// Start enabling TOTP for the signed in account by generating a new secret. TOTP is only
+/// required after it got confirmed with a first code.
+#[instrument(skip_all)]
+pub async fn enrol(info: &ConnectionInfo, connection: &DatabaseConnection) -> Result<Enrolment> {
+    let account = crate::session::account(connection).await?;
+
+    let enabled: Option<bool> = info
+        .connection
+        .statement(
+            "checking TOTP",
+            "RETURN $account.totp_enabled_at IS NOT NONE",
+        )
+        .bind(("account", &account))
+        .await?
+        .take(0)?;
+    if enabled.unwrap_or_default() {
+        return Err(ApplicationError::BadRequest(
+            "TOTP is already enabled".to_owned(),
+        ));
+    }
+
+    info.connection
+        .statement(
+            "storing account secret",
+            "UPDATE $account SET secret = $secret, totp_last_step = NONE",
+        )
+        .bind(("account", &account))
+        .bind(("secret", generate_secret()?))
+        .await?;
+
+    let (totp, _) = load(info, &account).await?;
+    let qr = totp.get_qr_base64().map_err(|error| {
+        error!("Unable to render QR code: {}", error);
+        ApplicationError::InternalServerError
+    })?;
+
+    Ok(Enrolment {
+        uri: totp.get_url(),
+        qr,
+    })
+}
+
+/// Enable TOTP for the signed in account with a first code of the enrolled secret. Returns the
+/// recovery codes, which are only stored hashed.
+#[instrument(skip(info, connection, code))]
+pub async fn confirm(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+    code: &str,
+) -> Result<Vec<String>> {
+    let account = crate::session::account(connection).await?;
+    let (totp, enabled) = load(info, &account).await?;
+    if enabled {
+        return Err(ApplicationError::BadRequest(
+            "TOTP is already enabled".to_owned(),
+        ));
+    }
+    if !use_code(info, &account, &totp, code).await? {
+        return Err(ApplicationError::Unauthorized);
+    }
+
+    let codes = (0..RECOVERY_CODES)
+        .map(|_| Alphanumeric.sample_string(&mut rand::thread_rng(), RECOVERY_CODE_LENGTH))
+        .collect::<Vec<String>>();
+    info.connection
+        .statement(
+            "enabling TOTP",
+            "UPDATE $account SET totp_enabled_at = time::now(), recovery_codes = []",
+        )
+        .query(
+            "FOR $code IN $codes {
+                UPDATE $account SET recovery_codes += crypto::sha256($code);
+            }",
+        )
+        .bind(("account", &account))
+        .bind(("codes", &codes))
+        .await?;
+
+    Ok(codes)
+}
+
+/// Check a TOTP or an unused recovery code of the given account. Recovery codes are only usable
+/// once.
+#[instrument(skip(info, code))]
+pub async fn verify(info: &ConnectionInfo, account: &Thing, code: &str) -> Result<bool> {
+    let (totp, _) = load(info, account).await?;
+    if use_code(info, account, &totp, code).await? {
+        return Ok(true);
+    }
+
+    let used: Vec<Thing> = info
+        .connection
+        .statement(
+            "using recovery code",
+            "UPDATE $account SET recovery_codes -= crypto::sha256($code)
+                WHERE recovery_codes CONTAINS crypto::sha256($code) RETURN VALUE id",
+        )
+        .bind(("account", account))
+        .bind(("code", code))
+        .await?
+        .take(0)?;
+
+    Ok(!used.is_empty())
+}
+
+/// Disable TOTP for the signed in account, which regenerates the secret.
+#[instrument(skip(info, connection, code))]
+pub async fn disable(
+    info: &ConnectionInfo,
+    connection: &DatabaseConnection,
+    code: &str,
+) -> Result<()> {
+    let account = crate::session::account(connection).await?;
+    let (_, enabled) = load(info, &account).await?;
+    if !enabled {
+        return Err(ApplicationError::BadRequest(
+            "TOTP is not enabled".to_owned(),
+        ));
+    }
+    if !verify(info, &account, code).await? {
+        return Err(ApplicationError::Unauthorized);
+    }
+
+    info.connection
+        .statement(
+            "disabling TOTP",
+            "UPDATE $account SET totp_enabled_at = NONE, recovery_codes = [], secret = $secret,
+                totp_last_step = NONE",
+        )
+        .bind(("account", &account))
+        .bind(("secret", generate_secret()?))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fixture::{customer, fresh, mail, session, CLIENT, PASSWORD};
+
+    #[tokio::test]
+    async fn test_totp() -> Result<()> {
+        let info = fresh().await?;
+        let alice = customer(&info, "alice").await?;
+        let connection = session(&info, "alice").await?;
+        let mail = &mail("alice");
+
+        let enrolment = enrol(&info, &connection).await?;
+        assert!(enrolment.uri.starts_with("otpauth://totp/"));
+        let (totp, _) = load(&info, &alice).await?;
+        // not enabled before the confirmation
+        crate::session::login(&info, CLIENT, mail, PASSWORD, None).await?;
+        assert!(confirm(&info, &connection, "000000").await.is_err());
+        let confirmed = totp.generate_current()?;
+        let codes = confirm(&info, &connection, confirmed.as_str()).await?;
+        assert_eq!(RECOVERY_CODES, codes.len());
+
+        // a login without the second factor is not possible anymore
+        assert!(session(&info, "alice").await.is_err());
+        assert!(matches!(
+            crate::session::login(&info, CLIENT, mail, PASSWORD, None).await,
+            Err(ApplicationError::SecondFactorRequired)
+        ));
+        // the code of the confirmation is used up, the one of the next step is valid as well
+        assert!(!verify(&info, &alice, confirmed.as_str()).await?);
+        let code = totp.generate(totp.next_step_current()?);
+        crate::session::login(&info, CLIENT, mail, PASSWORD, Some(code.as_str())).await?;
+        assert!(!verify(&info, &alice, code.as_str()).await?);
+        crate::session::login(&info, CLIENT, mail, PASSWORD, Some(codes[0].as_str())).await?;
+        assert!(verify(&info, &alice, codes[0].as_str())
+            .await
+            .is_ok_and(|valid| !valid));
+
+        disable(&info, &connection, codes[1].as_str()).await?;
+        let (regenerated, _) = load(&info, &alice).await?;
+        assert_ne!(totp.get_secret_base32(), regenerated.get_secret_base32());
+        session(&info, "alice").await?;
+
+        Ok(())
+    }
+}
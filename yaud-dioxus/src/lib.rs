@@ -1,14 +1,41 @@
 use dioxus::prelude::*;
 use dioxus_fullstack::prelude::*;
+use serde::Deserialize;
+
+/// The type of the signed in account, handed to the client along with the tokens of its session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountType {
+    Customer,
+    Employee,
+}
 
 pub fn app(cx: Scope) -> Element {
-    let mut count = use_state(cx, || 0);
+    // known once the account signed in
+    let account_type = use_state(cx, || None::<AccountType>);
+
+    match account_type.get() {
+        Some(account_type) => cx.render(rsx! {
+            Dashboard { account_type: *account_type }
+        }),
+        None => cx.render(rsx! {
+            h1 { "Sign in" }
+        }),
+    }
+}
 
-    cx.render(rsx! {
-        h1 { "High-Five counter: {count}" }
-        button { onclick: move |_| count += 1, "Up high!" }
-        button { onclick: move |_| count -= 1, "Down low!" }
-    })
+/// Customers only see their own requests and tasks, employees those of everyone.
+#[allow(non_snake_case)]
+#[inline_props]
+fn Dashboard(cx: Scope, account_type: AccountType) -> Element {
+    match account_type {
+        AccountType::Customer => cx.render(rsx! {
+            h1 { "Your requests" }
+        }),
+        AccountType::Employee => cx.render(rsx! {
+            h1 { "Tasks" }
+        }),
+    }
 }
 
 pub fn launch() {